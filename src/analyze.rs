@@ -0,0 +1,418 @@
+use crate::{
+    energy::integrate,
+    measure::{OutputFormat, Sample, mean_and_standard_deviation, toml_list},
+    tariff::{Tariff, format_cost},
+};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{io, path::Path, time::Duration};
+
+/// A single reading from a trace, as written by `monitor --record`, `monitor --output` or
+/// `measure --format csv`. Other columns are ignored.
+#[derive(Deserialize)]
+struct Row {
+    timestamp: DateTime<Utc>,
+    /// Traces of a single device may not name it.
+    device: Option<String>,
+    /// Missing while the device couldn't be reached.
+    watts: Option<u64>,
+}
+
+/// A device's reading at some point in time, `None` if it couldn't be reached.
+type Reading = (DateTime<Utc>, Option<u64>);
+
+/// What to look for in a trace.
+pub struct Options {
+    /// Only look at readings from this time on.
+    pub from: Option<DateTime<Utc>>,
+    /// Only look at readings before this time.
+    pub to: Option<DateTime<Utc>>,
+    /// The power above which the load counts as on for the duty cycle. Halfway between the
+    /// minimum and maximum by default, which suits loads switching between two levels, like a
+    /// fridge's compressor.
+    pub on_above: Option<f64>,
+    /// Report how long the power was above this.
+    pub threshold: Option<f64>,
+}
+
+/// Statistics over the readings of one device in a trace. Only ever add fields to it, scripts
+/// depend on its shape.
+#[derive(Debug, Serialize)]
+pub struct Analysis {
+    pub device: String,
+    /// The first and last reading looked at.
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// How long we have readings for, which excludes gaps between `from` and `to`.
+    pub observed_s: f64,
+    pub sample_count: usize,
+    /// How many records have no reading, because the device couldn't be reached.
+    pub missing_count: usize,
+    pub mean_w: f32,
+    pub stddev_w: f32,
+    pub min_w: u64,
+    pub max_w: u64,
+    pub p50_w: u64,
+    pub p90_w: u64,
+    pub p95_w: u64,
+    pub p99_w: u64,
+    /// Energy consumed between the first and the last reading, bridging gaps.
+    pub energy_wh: f64,
+    /// What `energy_wh` cost, if a tariff is configured.
+    pub cost: Option<f64>,
+    pub currency: Option<String>,
+    /// The share of the time the power was above `on_above_w`, missing if the power never changed
+    /// and no level was given.
+    pub duty_cycle: Option<f64>,
+    pub on_above_w: Option<f64>,
+    /// How often the power rose above `on_above_w`.
+    pub on_cycles: Option<usize>,
+    pub threshold_w: Option<f64>,
+    /// How long the power was above `threshold_w`.
+    pub time_above_threshold_s: Option<f64>,
+}
+
+/// Analyzes the readings of each device in the CSV or NDJSON trace at `path`.
+pub fn analyze(path: &Path, options: &Options, tariff: Option<Tariff>) -> Result<Vec<Analysis>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading trace {}", path.display()))?;
+    let rows = if contents.trim_start().starts_with('{') {
+        parse_ndjson(&contents, path)?
+    } else {
+        parse_csv(&contents, path)?
+    };
+
+    // Devices in the order they first appear.
+    let unnamed = path
+        .file_stem()
+        .map_or("trace".into(), |stem| stem.to_string_lossy());
+    let mut devices: Vec<(String, Vec<Reading>)> = Vec::new();
+    for row in rows {
+        if options.from.is_some_and(|from| row.timestamp < from)
+            || options.to.is_some_and(|to| row.timestamp >= to)
+        {
+            continue;
+        }
+        let device = row.device.unwrap_or_else(|| unnamed.to_string());
+        let readings = match devices.iter().position(|(name, _)| *name == device) {
+            Some(index) => &mut devices[index].1,
+            None => {
+                devices.push((device, Vec::new()));
+                &mut devices.last_mut().expect("just pushed").1
+            }
+        };
+        readings.push((row.timestamp, row.watts));
+    }
+
+    let analyses: Vec<Analysis> = devices
+        .into_iter()
+        .filter_map(|(device, mut readings)| {
+            readings.sort_by_key(|(timestamp, _)| *timestamp);
+            Analysis::new(device, &readings, options, tariff.clone())
+        })
+        .collect();
+    if analyses.is_empty() {
+        bail!(
+            "{} has no readings{}",
+            path.display(),
+            if options.from.is_some() || options.to.is_some() {
+                " in the given time window"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(analyses)
+}
+
+fn parse_ndjson(contents: &str, path: &Path) -> Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: serde_json::Value = serde_json::from_str(line)
+            .with_context(|| format!("{}:{}: not JSON", path.display(), index + 1))?;
+        // Recordings describe the devices before their readings.
+        if value["type"] == "device" {
+            continue;
+        }
+        rows.push(
+            serde_json::from_value(value)
+                .with_context(|| format!("{}:{}: not a reading", path.display(), index + 1))?,
+        );
+    }
+    Ok(rows)
+}
+
+fn parse_csv(contents: &str, path: &Path) -> Result<Vec<Row>> {
+    csv::Reader::from_reader(contents.as_bytes())
+        .deserialize()
+        .enumerate()
+        .map(|(index, row)| {
+            // The header is the first line.
+            row.with_context(|| format!("{}:{}: not a reading", path.display(), index + 2))
+        })
+        .collect()
+}
+
+impl Analysis {
+    /// Statistics over the readings, ordered by time. `None` if there are none.
+    fn new(
+        device: String,
+        readings: &[Reading],
+        options: &Options,
+        tariff: Option<Tariff>,
+    ) -> Option<Self> {
+        let samples: Vec<Sample> = readings
+            .iter()
+            .filter_map(|&(timestamp, watts)| {
+                Some(Sample {
+                    timestamp,
+                    watts: watts?,
+                })
+            })
+            .collect();
+        let mut watts: Vec<u64> = samples.iter().map(|sample| sample.watts).collect();
+        watts.sort_unstable();
+        let (&min, &max) = (watts.first()?, watts.last()?);
+        let (mean, standard_deviation) = mean_and_standard_deviation(&watts);
+        let percentile = |percent: usize| watts[(percent * watts.len()).div_ceil(100).max(1) - 1];
+
+        let energy = integrate(&samples, tariff.clone());
+
+        let observed = time_above(readings, f64::INFINITY).observed;
+        let on_above = options
+            .on_above
+            .or_else(|| (min < max).then(|| (min + max) as f64 / 2.0));
+        let on = on_above.map(|level| time_above(readings, level));
+        let above_threshold = options.threshold.map(|level| time_above(readings, level));
+
+        Some(Self {
+            device,
+            from: samples[0].timestamp,
+            to: samples[samples.len() - 1].timestamp,
+            observed_s: observed.as_secs_f64(),
+            sample_count: samples.len(),
+            missing_count: readings.len() - samples.len(),
+            mean_w: mean,
+            stddev_w: standard_deviation,
+            min_w: min,
+            max_w: max,
+            p50_w: percentile(50),
+            p90_w: percentile(90),
+            p95_w: percentile(95),
+            p99_w: percentile(99),
+            energy_wh: energy.watt_hours(),
+            cost: energy.cost(),
+            currency: tariff.map(|tariff| tariff.currency),
+            duty_cycle: on.map(|on| on.share()),
+            on_above_w: on_above,
+            on_cycles: on.map(|on| on.rises),
+            threshold_w: options.threshold,
+            time_above_threshold_s: above_threshold.map(|above| above.time.as_secs_f64()),
+        })
+    }
+}
+
+/// How long, and how often, the power was above some level.
+#[derive(Clone, Copy)]
+struct TimeAbove {
+    time: Duration,
+    /// How long we have readings for.
+    observed: Duration,
+    rises: usize,
+}
+
+impl TimeAbove {
+    fn share(&self) -> f64 {
+        if self.observed.is_zero() {
+            return 0.0;
+        }
+        self.time.as_secs_f64() / self.observed.as_secs_f64()
+    }
+}
+
+/// Each reading holds until the next one. Time without readings doesn't count.
+fn time_above(readings: &[Reading], level: f64) -> TimeAbove {
+    let mut time_above = TimeAbove {
+        time: Duration::ZERO,
+        observed: Duration::ZERO,
+        rises: 0,
+    };
+    let mut was_above = false;
+    for pair in readings.windows(2) {
+        let [(start, watts), (end, _)] = pair else {
+            unreachable!("windows of two");
+        };
+        let Some(watts) = watts else {
+            // We can't tell whether it dropped below in the meantime, going above again after the
+            // gap counts as a rise.
+            was_above = false;
+            continue;
+        };
+        let duration = (*end - *start).to_std().unwrap_or_default();
+        time_above.observed += duration;
+        let is_above = *watts as f64 > level;
+        if is_above {
+            time_above.time += duration;
+            if !was_above {
+                time_above.rises += 1;
+            }
+        }
+        was_above = is_above;
+    }
+    time_above
+}
+
+pub fn print(analyses: &[Analysis], format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Human => {
+            for (index, analysis) in analyses.iter().enumerate() {
+                if index > 0 {
+                    println!();
+                }
+                print_human(analysis);
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(analyses)?),
+        OutputFormat::Toml => print!("{}", toml_list("devices", analyses)?),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            for analysis in analyses {
+                writer.serialize(analysis)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn print_human(analysis: &Analysis) {
+    let local = |timestamp: DateTime<Utc>| {
+        timestamp
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    let duration = (analysis.to - analysis.from).to_std().unwrap_or_default();
+    println!(
+        "{}: {} to {} ({}), {} samples{}",
+        analysis.device,
+        local(analysis.from),
+        local(analysis.to),
+        humantime::format_duration(Duration::from_secs(duration.as_secs())),
+        analysis.sample_count,
+        match analysis.missing_count {
+            0 => String::new(),
+            missing => format!(", {missing} missing"),
+        }
+    );
+    println!("avg: {:.1} W +-{:.1} W", analysis.mean_w, analysis.stddev_w);
+    println!("min: {} W", analysis.min_w);
+    println!("max: {} W", analysis.max_w);
+    println!(
+        "percentiles: p50 {} W, p90 {} W, p95 {} W, p99 {} W",
+        analysis.p50_w, analysis.p90_w, analysis.p95_w, analysis.p99_w
+    );
+    println!("energy: {:.4} Wh", analysis.energy_wh);
+    if let Some(cost) = analysis.cost {
+        let currency = analysis.currency.as_deref().unwrap_or_default();
+        println!("cost: {}", format_cost(cost, currency));
+    }
+    if let (Some(duty_cycle), Some(on_above), Some(cycles)) =
+        (analysis.duty_cycle, analysis.on_above_w, analysis.on_cycles)
+    {
+        println!(
+            "duty cycle: {:.1}% above {on_above:.1} W, {cycles} {}",
+            duty_cycle * 100.0,
+            if cycles == 1 { "cycle" } else { "cycles" }
+        );
+    }
+    if let (Some(threshold), Some(seconds)) =
+        (analysis.threshold_w, analysis.time_above_threshold_s)
+    {
+        println!(
+            "above {threshold} W: {} ({:.1}%)",
+            humantime::format_duration(Duration::from_secs(seconds as u64)),
+            if analysis.observed_s > 0.0 {
+                seconds / analysis.observed_s * 100.0
+            } else {
+                0.0
+            }
+        );
+    }
+}
+
+/// Parses `--from` and `--to`, either RFC 3339 like `2025-06-01T18:00:00Z` or local time like
+/// `2025-06-01 18:00`.
+pub fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.to_utc());
+    }
+    let local = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(time, format).ok())
+        .with_context(|| {
+            format!("Invalid time {time:?}, expected e.g. 2025-06-01 18:00 or 2025-06-01T18:00:00Z")
+        })?;
+    local
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|time| time.to_utc())
+        .with_context(|| format!("{time:?} doesn't exist in the local time zone"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    /// Readings a second apart.
+    fn readings(watts: &[Option<u64>]) -> Vec<Reading> {
+        watts
+            .iter()
+            .enumerate()
+            .map(|(second, &watts)| {
+                (
+                    DateTime::UNIX_EPOCH + TimeDelta::seconds(second as i64),
+                    watts,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn counts_time_and_rises_above_the_level() {
+        let readings = readings(&[Some(5), Some(50), Some(60), Some(5), Some(50), Some(5)]);
+        let above = time_above(&readings, 10.0);
+        assert_eq!(above.time, Duration::from_secs(3));
+        assert_eq!(above.observed, Duration::from_secs(5));
+        assert_eq!(above.rises, 2);
+    }
+
+    #[test]
+    fn being_at_the_level_is_not_above() {
+        let above = time_above(&readings(&[Some(10), Some(10)]), 10.0);
+        assert_eq!(above.time, Duration::ZERO);
+        assert_eq!(above.rises, 0);
+    }
+
+    #[test]
+    fn gaps_are_not_observed() {
+        let readings = readings(&[Some(50), None, None, Some(5), Some(5)]);
+        let above = time_above(&readings, 10.0);
+        assert_eq!(above.time, Duration::from_secs(1));
+        assert_eq!(above.observed, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn rising_again_after_a_gap() {
+        let readings = readings(&[Some(50), Some(50), None, Some(50), Some(50)]);
+        let above = time_above(&readings, 10.0);
+        assert_eq!(above.time, Duration::from_secs(3));
+        assert_eq!(above.rises, 2);
+    }
+}
@@ -0,0 +1,75 @@
+//! The device side of UDP discovery: the client broadcasts a probe with a binary header and a JSON
+//! body, and every device answers with its address, model and how to reach its local API.
+
+use crate::{Emulator, Protocol};
+use anyhow::Result;
+use serde_json::json;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::UdpSocket;
+
+/// Probes and answers start with a header of this size, followed by JSON.
+const HEADER_LEN: usize = 16;
+
+/// Answers discovery probes on `socket` for the device whose API listens on `api_address`.
+pub async fn serve(
+    socket: UdpSocket,
+    emulator: Arc<Emulator>,
+    api_address: SocketAddr,
+) -> Result<()> {
+    let mut buffer = vec![0; 4096];
+    loop {
+        let (len, source) = socket.recv_from(&mut buffer).await?;
+        if !is_probe(&buffer[..len]) {
+            eprintln!("Ignoring malformed discovery probe from {source}");
+            continue;
+        }
+
+        emulator.delay().await;
+        socket
+            .send_to(&response(&emulator, api_address), source)
+            .await?;
+    }
+}
+
+/// Whether `packet` has a valid checksum, which is computed with the checksum field set to a
+/// fixed initial value.
+fn is_probe(packet: &[u8]) -> bool {
+    if packet.len() < HEADER_LEN {
+        return false;
+    }
+    let mut unchecked = packet.to_vec();
+    unchecked[12..HEADER_LEN].copy_from_slice(&0x5A6B_7C8Du32.to_be_bytes());
+    crc32fast::hash(&unchecked).to_be_bytes() == packet[12..HEADER_LEN]
+}
+
+fn response(emulator: &Emulator, api_address: SocketAddr) -> Vec<u8> {
+    let body = json!({
+        "error_code": 0,
+        "result": {
+            "device_id": "80220000000000000000000000000000000000EMU",
+            "device_type": "SMART.TAPOPLUG",
+            "device_model": format!("{}(EU)", emulator.args.model),
+            "ip": api_address.ip(),
+            "mac": "00-00-5E-00-53-01",
+            "mgt_encrypt_schm": {
+                "is_support_https": false,
+                "encrypt_type": match emulator.args.protocol {
+                    Protocol::Klap => "KLAP",
+                    Protocol::Passthrough => "AES",
+                },
+                "http_port": api_address.port(),
+                "lv": 2,
+            },
+        },
+    })
+    .to_string();
+
+    // Version 2, a response to a probe, with the same flags as real devices. No checksum, clients
+    // don't check it.
+    let mut response = vec![2, 0, 0, 1];
+    response.extend((body.len() as u16).to_be_bytes());
+    response.extend([17, 0]);
+    response.extend([0; 8]);
+    response.extend(body.as_bytes());
+    response
+}
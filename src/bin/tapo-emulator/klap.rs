@@ -0,0 +1,107 @@
+//! The device side of the KLAP protocol: a two step handshake proving both ends know the account
+//! credentials, followed by AES encrypted requests keyed off the exchanged seeds.
+
+use aes::{
+    Aes128,
+    cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::Pkcs7},
+};
+use cbc::{Decryptor, Encryptor};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::time::Instant;
+
+pub struct Session {
+    local_seed: [u8; 16],
+    remote_seed: [u8; 16],
+    auth_hash: [u8; 32],
+    /// Only available once the client completed the second handshake.
+    cipher: Option<Cipher>,
+    pub started: Instant,
+}
+
+impl Session {
+    pub fn new(local_seed: [u8; 16], auth_hash: [u8; 32]) -> Self {
+        Self {
+            local_seed,
+            remote_seed: rand::random(),
+            auth_hash,
+            cipher: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Our seed followed by a hash the client can use to check we know the credentials.
+    pub fn handshake1_response(&self) -> Vec<u8> {
+        let server_hash = sha256(&[&self.local_seed, &self.remote_seed, &self.auth_hash[..]]);
+        [&self.remote_seed[..], &server_hash].concat()
+    }
+
+    /// Checks the client's proof of knowing the credentials and sets up the session cipher.
+    pub fn handshake2(&mut self, client_hash: &[u8]) -> bool {
+        let expected = sha256(&[&self.remote_seed, &self.local_seed, &self.auth_hash[..]]);
+        if client_hash != expected {
+            return false;
+        }
+
+        self.cipher = Some(Cipher::new(&[
+            &self.local_seed,
+            &self.remote_seed,
+            &self.auth_hash,
+        ]));
+        true
+    }
+
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_ref()
+    }
+}
+
+pub struct Cipher {
+    key: [u8; 16],
+    iv: [u8; 12],
+    signature: [u8; 28],
+}
+
+impl Cipher {
+    fn new(seeds: &[&[u8]]) -> Self {
+        let derive = |prefix: &[u8]| sha256(&[&[prefix][..], seeds].concat());
+
+        Self {
+            key: derive(b"lsk")[..16].try_into().unwrap(),
+            iv: derive(b"iv")[..12].try_into().unwrap(),
+            signature: derive(b"ldk")[..28].try_into().unwrap(),
+        }
+    }
+
+    fn iv_for(&self, seq: i32) -> [u8; 16] {
+        [&self.iv[..], &seq.to_be_bytes()]
+            .concat()
+            .try_into()
+            .unwrap()
+    }
+
+    /// Requests are a 32 byte signature followed by the AES-128-CBC encrypted payload.
+    pub fn decrypt(&self, seq: i32, payload: &[u8]) -> Option<String> {
+        let cipher_bytes = payload.get(32..)?;
+        let plain_bytes = Decryptor::<Aes128>::new(&self.key.into(), &self.iv_for(seq).into())
+            .decrypt_padded_vec_mut::<Pkcs7>(cipher_bytes)
+            .ok()?;
+        String::from_utf8(plain_bytes).ok()
+    }
+
+    pub fn encrypt(&self, seq: i32, data: &str) -> Vec<u8> {
+        let cipher_bytes = Encryptor::<Aes128>::new(&self.key.into(), &self.iv_for(seq).into())
+            .encrypt_padded_vec_mut::<Pkcs7>(data.as_bytes());
+        let signature = sha256(&[&self.signature, &seq.to_be_bytes(), &cipher_bytes]);
+        [&signature[..], &cipher_bytes].concat()
+    }
+}
+
+pub fn auth_hash(username: &str, password: &str) -> [u8; 32] {
+    let sha1 = |value: &str| Sha1::digest(value.as_bytes());
+    sha256(&[&sha1(username), &sha1(password)])
+}
+
+fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    Sha256::digest(parts.concat()).into()
+}
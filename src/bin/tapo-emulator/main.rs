@@ -0,0 +1,409 @@
+//! A stand-in for a Tapo P115 smart plug speaking the same local HTTP API as the real thing, so
+//! that `tapo-power-monitor` can be exercised end to end without any hardware.
+//!
+//! Run with `cargo run --features emulator --bin tapo-emulator` and point `tapo-power-monitor` at
+//! it with `cargo run -- 127.0.0.1 --port 8080 measure`.
+
+use anyhow::{Context, Result};
+use axum::{
+    Router,
+    body::Bytes,
+    extract::{Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use chrono::{DateTime, Datelike, Days, Local, Months, NaiveDate, TimeDelta};
+use clap::{Parser, ValueEnum};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    time::sleep,
+};
+
+mod discovery;
+mod klap;
+mod passthrough;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("Binding to {}", args.listen))?;
+    // Tests bind to port 0 and read the actual address from here.
+    let api_address = listener.local_addr()?;
+    println!("Listening on {api_address}");
+    let discovery = match args.discovery {
+        Some(address) => {
+            let socket = UdpSocket::bind(address)
+                .await
+                .with_context(|| format!("Binding to {address}"))?;
+            println!("Discovery on {}", socket.local_addr()?);
+            Some(socket)
+        }
+        None => None,
+    };
+
+    let emulator = Arc::new(Emulator::new(args));
+    if let Some(socket) = discovery {
+        let emulator = emulator.clone();
+        tokio::spawn(async move {
+            if let Err(error) = discovery::serve(socket, emulator, api_address).await {
+                eprintln!("Discovery failed: {error:#}");
+            }
+        });
+    }
+
+    let app = Router::new()
+        .route("/app", post(app))
+        .route("/app/handshake1", post(handshake1))
+        .route("/app/handshake2", post(handshake2))
+        .route("/app/request", post(request))
+        .with_state(emulator);
+
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+/// The two flavours of the local API. Newer firmware only speaks KLAP.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Protocol {
+    Klap,
+    Passthrough,
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Emulates a Tapo P115 smart plug on the local network")]
+struct Args {
+    /// Address to listen on. Use port 0 to pick any free port.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+    /// Also answer UDP discovery requests on this address, e.g. `0.0.0.0:20002`.
+    #[arg(long)]
+    discovery: Option<SocketAddr>,
+    /// Tapo account username the emulated device accepts.
+    #[arg(long, env = "TAPO_USERNAME")]
+    username: String,
+    /// Tapo account password the emulated device accepts.
+    #[arg(long, env = "TAPO_PASSWORD", hide_env_values = true)]
+    password: String,
+    #[arg(long, value_enum, default_value_t = Protocol::Klap)]
+    protocol: Protocol,
+    /// The constant power consumption reported by the device, in Watts.
+    #[arg(long, default_value_t = 42)]
+    watts: u64,
+    #[arg(long, default_value = "P115")]
+    model: String,
+    #[arg(long, default_value = "Emulated plug")]
+    nickname: String,
+    /// Expire sessions after this long, e.g. `10s`, so that clients have to log in again.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    session_lifetime: Option<Duration>,
+    /// Delay every response by this long, e.g. `5s`, to provoke client timeouts.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    response_delay: Option<Duration>,
+}
+
+struct Emulator {
+    args: Args,
+    started: Instant,
+    klap_sessions: Mutex<HashMap<String, klap::Session>>,
+    passthrough_sessions: Mutex<HashMap<String, passthrough::Session>>,
+}
+
+impl Emulator {
+    fn new(args: Args) -> Self {
+        Self {
+            args,
+            started: Instant::now(),
+            klap_sessions: Mutex::default(),
+            passthrough_sessions: Mutex::default(),
+        }
+    }
+
+    async fn delay(&self) {
+        if let Some(delay) = self.args.response_delay {
+            sleep(delay).await;
+        }
+    }
+
+    /// Whether a session started at `started` has outlived --session-lifetime.
+    fn is_expired(&self, started: Instant) -> bool {
+        self.args
+            .session_lifetime
+            .is_some_and(|lifetime| started.elapsed() > lifetime)
+    }
+
+    /// Answers a decrypted request with a Tapo response envelope, as the device would.
+    fn handle(&self, request: &Value) -> Value {
+        let elapsed = self.started.elapsed();
+        let watts = self.args.watts;
+        let now = Local::now();
+        let today = midnight(now.date_naive());
+        let this_month = midnight(
+            now.date_naive()
+                .with_day(1)
+                .expect("months have a first day"),
+        );
+
+        let result = match request["method"].as_str().unwrap_or_default() {
+            "get_current_power" => json!({ "current_power": watts }),
+            "get_energy_usage" => json!({
+                "local_time": local_time(now),
+                "current_power": watts * 1000,
+                "today_runtime": (now - today).num_minutes(),
+                "today_energy": self.energy_between(today, now),
+                "month_runtime": (now - this_month).num_minutes(),
+                "month_energy": self.energy_between(this_month, now),
+            }),
+            "get_energy_data" => match self.energy_data(&request["params"]) {
+                Some(result) => result,
+                None => return json!({ "error_code": -1008 }),
+            },
+            "get_device_info" => self.device_info(elapsed),
+            method => {
+                eprintln!("Unsupported method {method:?}");
+                return json!({ "error_code": -1002 });
+            }
+        };
+
+        json!({ "error_code": 0, "result": result })
+    }
+
+    /// Watt hours consumed between `start` and `end`, pretending the device has always been drawing
+    /// the same power. Nothing is consumed in the future.
+    fn energy_between(&self, start: DateTime<Local>, end: DateTime<Local>) -> u64 {
+        let hours = (end.min(Local::now()) - start).as_seconds_f64().max(0.0) / 3600.0;
+        (self.args.watts as f64 * hours) as u64
+    }
+
+    /// Hourly, daily or monthly history, depending on the requested interval in minutes.
+    fn energy_data(&self, params: &Value) -> Option<Value> {
+        let start_timestamp = params["start_timestamp"].as_i64()?;
+        let end_timestamp = params["end_timestamp"].as_i64()?;
+        let interval = params["interval"].as_u64()?;
+        let start = DateTime::from_timestamp(start_timestamp, 0)?.with_timezone(&Local);
+
+        let periods: Vec<_> = match interval {
+            60 => {
+                let hours = (end_timestamp - start_timestamp + 1) / 3600;
+                (0..hours)
+                    .map(|hour| {
+                        (
+                            start + TimeDelta::hours(hour),
+                            start + TimeDelta::hours(hour + 1),
+                        )
+                    })
+                    .collect()
+            }
+            // A quarter starting at `start`.
+            1440 => {
+                let first = start.date_naive();
+                first
+                    .iter_days()
+                    .take_while(|date| *date < first + Months::new(3))
+                    .map(|date| (midnight(date), midnight(date + Days::new(1))))
+                    .collect()
+            }
+            // A year starting at `start`.
+            43200 => (0..12)
+                .map(|month| {
+                    (
+                        midnight(start.date_naive() + Months::new(month)),
+                        midnight(start.date_naive() + Months::new(month + 1)),
+                    )
+                })
+                .collect(),
+            _ => return None,
+        };
+
+        let data: Vec<u64> = periods
+            .into_iter()
+            .map(|(start, end)| self.energy_between(start, end))
+            .collect();
+        Some(json!({
+            "local_time": local_time(Local::now()),
+            "data": data,
+            "start_timestamp": start_timestamp,
+            "end_timestamp": end_timestamp,
+            "interval": interval,
+        }))
+    }
+
+    fn device_info(&self, elapsed: Duration) -> Value {
+        json!({
+            "device_id": "80220000000000000000000000000000000000EMU",
+            "type": "SMART.TAPOPLUG",
+            "model": self.args.model,
+            "hw_id": "00000000000000000000000000000000",
+            "hw_ver": "1.0",
+            "fw_id": "00000000000000000000000000000000",
+            "fw_ver": "1.0.0 Build 000000 Rel.00000",
+            "oem_id": "00000000000000000000000000000000",
+            "mac": "00-00-5E-00-53-01",
+            "ip": "127.0.0.1",
+            "ssid": encode("emulated-network"),
+            "signal_level": 3,
+            "rssi": -40,
+            "specs": "",
+            "lang": "en_US",
+            "device_on": true,
+            "on_time": elapsed.as_secs(),
+            "nickname": encode(&self.args.nickname),
+            "avatar": "plug",
+            "has_set_location_info": false,
+            "default_states": { "type": "last_states", "state": {} },
+            "overcurrent_status": "normal",
+            "overheat_status": "normal",
+            "power_protection_status": "normal",
+            "charging_status": "normal",
+        })
+    }
+}
+
+fn midnight(date: NaiveDate) -> DateTime<Local> {
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|time| time.and_local_timezone(Local).earliest())
+        .expect("midnight exists")
+}
+
+/// The device reports times in this format, without a time zone.
+fn local_time(time: DateTime<Local>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// The device base64-encodes user-provided strings.
+fn encode(value: &str) -> String {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    STANDARD.encode(value)
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    let cookies = headers.get(header::COOKIE)?.to_str().ok()?;
+    cookies
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == "TP_SESSIONID")
+        .map(|(_, value)| value.to_string())
+}
+
+fn new_session_id() -> String {
+    format!("{:032X}", rand::random::<u128>())
+}
+
+fn with_session_cookie(session_id: &str, response: impl IntoResponse) -> Response {
+    let mut response = response.into_response();
+    let cookie = format!("TP_SESSIONID={session_id};TIMEOUT=86400");
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&cookie).expect("valid header value"),
+    );
+    response
+}
+
+/// Plain JSON endpoint, used for protocol discovery and the whole passthrough protocol.
+async fn app(
+    State(emulator): State<Arc<Emulator>>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    emulator.delay().await;
+
+    let Ok(request) = serde_json::from_slice::<Value>(&body) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    if emulator.args.protocol == Protocol::Klap {
+        // This is how KLAP devices tell clients they should use the other endpoints.
+        return axum::Json(json!({ "error_code": 1003 })).into_response();
+    }
+
+    passthrough::handle(&emulator, &headers, query.get("token"), &request)
+}
+
+async fn handshake1(State(emulator): State<Arc<Emulator>>, body: Bytes) -> Response {
+    emulator.delay().await;
+    if emulator.args.protocol != Protocol::Klap {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let Ok(local_seed) = <[u8; 16]>::try_from(body.as_ref()) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let auth_hash = klap::auth_hash(&emulator.args.username, &emulator.args.password);
+    let session = klap::Session::new(local_seed, auth_hash);
+    let response = session.handshake1_response();
+
+    let session_id = new_session_id();
+    emulator
+        .klap_sessions
+        .lock()
+        .unwrap()
+        .insert(session_id.clone(), session);
+
+    with_session_cookie(&session_id, response)
+}
+
+async fn handshake2(
+    State(emulator): State<Arc<Emulator>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    emulator.delay().await;
+
+    let mut sessions = emulator.klap_sessions.lock().unwrap();
+    let Some(session) = session_cookie(&headers).and_then(|id| sessions.get_mut(&id)) else {
+        return StatusCode::FORBIDDEN;
+    };
+
+    if session.handshake2(&body) {
+        StatusCode::OK
+    } else {
+        StatusCode::FORBIDDEN
+    }
+}
+
+async fn request(
+    State(emulator): State<Arc<Emulator>>,
+    Query(query): Query<HashMap<String, i32>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    emulator.delay().await;
+
+    let Some(&seq) = query.get("seq") else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let sessions = emulator.klap_sessions.lock().unwrap();
+    // Real devices also answer expired sessions with 403.
+    let Some(cipher) = session_cookie(&headers)
+        .and_then(|id| sessions.get(&id))
+        .filter(|session| !emulator.is_expired(session.started))
+        .and_then(klap::Session::cipher)
+    else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+
+    let Some(request) = cipher
+        .decrypt(seq, &body)
+        .and_then(|request| serde_json::from_str::<Value>(&request).ok())
+    else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    cipher
+        .encrypt(seq, &emulator.handle(&request).to_string())
+        .into_response()
+}
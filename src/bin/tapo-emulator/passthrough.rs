@@ -0,0 +1,142 @@
+//! The device side of the older "securePassthrough" protocol: the client sends an RSA public key,
+//! we answer with an encrypted AES key, and every following request is wrapped in an encrypted
+//! `securePassthrough` envelope. Logging in hands out a token the client appends to the URL.
+
+use crate::{Emulator, new_session_id, session_cookie, with_session_cookie};
+use aes::{
+    Aes128,
+    cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::Pkcs7},
+};
+use axum::{
+    Json,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use cbc::{Decryptor, Encryptor};
+use rsa::{Pkcs1v15Encrypt, RsaPublicKey, pkcs8::DecodePublicKey, rand_core::OsRng};
+use serde_json::{Value, json};
+use sha1::{Digest, Sha1};
+use std::time::Instant;
+
+pub struct Session {
+    key: [u8; 16],
+    iv: [u8; 16],
+    token: Option<String>,
+    started: Instant,
+}
+
+impl Session {
+    fn decrypt(&self, request: &str) -> Option<Value> {
+        let cipher_bytes = STANDARD.decode(request).ok()?;
+        let plain_bytes = Decryptor::<Aes128>::new(&self.key.into(), &self.iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(&cipher_bytes)
+            .ok()?;
+        serde_json::from_slice(&plain_bytes).ok()
+    }
+
+    fn encrypt(&self, response: &Value) -> String {
+        let cipher_bytes = Encryptor::<Aes128>::new(&self.key.into(), &self.iv.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(response.to_string().as_bytes());
+        STANDARD.encode(cipher_bytes)
+    }
+}
+
+pub fn handle(
+    emulator: &Emulator,
+    headers: &HeaderMap,
+    token: Option<&String>,
+    request: &Value,
+) -> Response {
+    match request["method"].as_str().unwrap_or_default() {
+        "component_nego" => Json(json!({ "error_code": 0, "result": {} })).into_response(),
+        "handshake" => handshake(emulator, request),
+        "securePassthrough" => secure_passthrough(emulator, headers, token, request),
+        _ => Json(json!({ "error_code": -1002 })).into_response(),
+    }
+}
+
+fn handshake(emulator: &Emulator, request: &Value) -> Response {
+    let Some(public_key) = request["params"]["key"]
+        .as_str()
+        .and_then(|pem| RsaPublicKey::from_public_key_pem(pem).ok())
+    else {
+        return Json(json!({ "error_code": -1010 })).into_response();
+    };
+
+    let session = Session {
+        key: rand::random(),
+        iv: rand::random(),
+        token: None,
+        started: Instant::now(),
+    };
+    let encrypted_key = public_key
+        .encrypt(
+            &mut OsRng,
+            Pkcs1v15Encrypt,
+            &[session.key, session.iv].concat(),
+        )
+        .expect("the key fits into a single RSA block");
+
+    let session_id = new_session_id();
+    emulator
+        .passthrough_sessions
+        .lock()
+        .unwrap()
+        .insert(session_id.clone(), session);
+
+    let response = json!({ "error_code": 0, "result": { "key": STANDARD.encode(encrypted_key) } });
+    with_session_cookie(&session_id, Json(response))
+}
+
+fn secure_passthrough(
+    emulator: &Emulator,
+    headers: &HeaderMap,
+    token: Option<&String>,
+    request: &Value,
+) -> Response {
+    let mut sessions = emulator.passthrough_sessions.lock().unwrap();
+    let Some(session) = session_cookie(headers).and_then(|id| sessions.get_mut(&id)) else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+
+    let Some(inner_request) = request["params"]["request"]
+        .as_str()
+        .and_then(|r| session.decrypt(r))
+    else {
+        return Json(json!({ "error_code": -1003 })).into_response();
+    };
+
+    let inner_response = if inner_request["method"] == "login_device" {
+        login(emulator, session, &inner_request["params"])
+    } else if session.token.is_some()
+        && session.token.as_ref() == token
+        && !emulator.is_expired(session.started)
+    {
+        emulator.handle(&inner_request)
+    } else {
+        // Not logged in (yet), or not anymore.
+        json!({ "error_code": 9999 })
+    };
+
+    let response =
+        json!({ "error_code": 0, "result": { "response": session.encrypt(&inner_response) } });
+    Json(response).into_response()
+}
+
+fn login(emulator: &Emulator, session: &mut Session, params: &Value) -> Value {
+    let username_digest: String = Sha1::digest(emulator.args.username.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    if params["username"] != STANDARD.encode(username_digest)
+        || params["password"] != STANDARD.encode(&emulator.args.password)
+    {
+        return json!({ "error_code": -1501 });
+    }
+
+    let token = new_session_id();
+    session.token = Some(token.clone());
+    json!({ "error_code": 0, "result": { "token": token } })
+}
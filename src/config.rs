@@ -0,0 +1,224 @@
+use crate::{measure::OutputFormat, tariff::Tariff};
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer, de::Error as _};
+use std::{
+    collections::BTreeMap,
+    env,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Settings read from `config.toml` in the user's config directory, or the file passed with
+/// `--config`. Command line arguments take precedence over all of them. For example
+///
+/// ```toml
+/// interval = "2s"
+/// format = "json"
+///
+/// [devices]
+/// lab-bench = "10.0.4.12"
+/// desk = { address = "10.0.4.20", model = "p110" }
+///
+/// [tariff]
+/// currency = "EUR"
+/// price_per_kwh = 0.30
+/// ```
+///
+/// It may also hold the credentials, see [`crate::credentials::CredentialArgs`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Devices by name, to use instead of their IP addresses.
+    #[serde(default)]
+    pub devices: BTreeMap<String, DeviceConfig>,
+    /// Default time between two samples.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub interval: Option<Duration>,
+    /// Default output format of `measure`, `analyze` and `query`.
+    pub format: Option<OutputFormat>,
+    pub tariff: Option<Tariff>,
+    pub username: Option<String>,
+    /// Only used if the config file isn't readable by everyone.
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub password_command: Option<String>,
+    /// Where the config was loaded from, if anywhere.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+/// A device in the config file, either just its address or a table with further details.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum DeviceConfig {
+    Address(DeviceAddress),
+    Detailed {
+        address: DeviceAddress,
+        #[serde(default)]
+        model: Model,
+    },
+}
+
+/// The IP address of a device, and optionally the port of its local API.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceAddress {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
+
+/// The kinds of smart plugs we can read from. The M variants are the same plugs with Matter
+/// support.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Model {
+    /// Whatever the device says it is.
+    #[default]
+    Auto,
+    #[value(alias = "p110m")]
+    #[serde(alias = "p110m")]
+    P110,
+    #[value(alias = "p115m")]
+    #[serde(alias = "p115m")]
+    P115,
+}
+
+impl Model {
+    /// Recognizes a model as reported by the device, e.g. `P115(EU)`.
+    pub fn from_device_model(device_model: &str) -> Option<Self> {
+        let model = device_model
+            .split('(')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        match model.trim_end_matches('M') {
+            "P110" => Some(Model::P110),
+            "P115" => Some(Model::P115),
+            _ => None,
+        }
+    }
+
+    /// Like [`Self::from_device_model`], explaining why we can't read from other models.
+    pub fn detect(device_model: &str) -> Result<Self> {
+        if let Some(model) = Self::from_device_model(device_model) {
+            return Ok(model);
+        }
+        if ["P300", "P304", "P306"]
+            .iter()
+            .any(|strip| device_model.to_ascii_uppercase().starts_with(strip))
+        {
+            bail!(
+                "The device is a {device_model} power strip. The Tapo library we use can't read \
+                    the power of its outlets yet, only P110 and P115 plugs are supported."
+            );
+        }
+        bail!(
+            "The device is a {device_model}, which doesn't measure power. Use a P110 or P115 plug."
+        )
+    }
+}
+
+impl Config {
+    /// Loads the config from `path`, or from the default location if none is given. A missing
+    /// file at the default location is fine, there is nothing to configure.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default());
+            }
+            Err(error) => {
+                return Err(error).with_context(|| format!("Reading config {}", path.display()));
+            }
+        };
+        let config: Self = toml::from_str(&contents)
+            .with_context(|| format!("Parsing config {}", path.display()))?;
+        Ok(Self {
+            path: Some(path),
+            ..config
+        })
+    }
+}
+
+impl DeviceConfig {
+    pub fn address(&self) -> DeviceAddress {
+        match self {
+            DeviceConfig::Address(address) | DeviceConfig::Detailed { address, .. } => *address,
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        match self {
+            DeviceConfig::Address(_) => Model::default(),
+            DeviceConfig::Detailed { model, .. } => *model,
+        }
+    }
+}
+
+impl DeviceAddress {
+    /// What the Tapo API client expects, `IP` or `IP:PORT`.
+    pub fn to_api_address(self) -> String {
+        match self.port {
+            Some(port) => SocketAddr::new(self.ip, port).to_string(),
+            None => self.ip.to_string(),
+        }
+    }
+}
+
+impl FromStr for DeviceAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(address: &str) -> Result<Self> {
+        if let Ok(address) = address.parse::<SocketAddr>() {
+            return Ok(Self {
+                ip: address.ip(),
+                port: Some(address.port()),
+            });
+        }
+        match address.parse() {
+            Ok(ip) => Ok(Self { ip, port: None }),
+            Err(_) => bail!("Invalid address {address:?}, expected IP[:PORT]"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let address = String::deserialize(deserializer)?;
+        address.parse().map_err(D::Error::custom)
+    }
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let duration = String::deserialize(deserializer)?;
+    humantime::parse_duration(&duration)
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+/// `$XDG_CONFIG_HOME/tapo-power-monitor/config.toml`, falling back to `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    Some(app_dir("XDG_CONFIG_HOME", ".config")?.join("config.toml"))
+}
+
+/// Our directory below the one named by the XDG base directory `variable`, e.g.
+/// `$XDG_CACHE_HOME/tapo-power-monitor`, or below `fallback` in the home directory if unset.
+pub fn app_dir(variable: &str, fallback: &str) -> Option<PathBuf> {
+    let base = env::var_os(variable)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(fallback)))?;
+    Some(base.join(env!("CARGO_PKG_NAME")))
+}
@@ -0,0 +1,179 @@
+use crate::power_source::{Device, EnergyUsage};
+use anyhow::Result;
+use chrono::{DateTime, Local, Utc};
+use std::time::{Duration, Instant};
+
+/// How long to wait before trying to reconnect for the first time. Doubles with every failed
+/// attempt, up to `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A device we keep reading from through Wi-Fi blips and expired sessions, for long running
+/// monitoring. Remembers when it couldn't be reached.
+pub struct Connection {
+    pub device: Device,
+    /// Set while the device can't be reached.
+    outage: Option<Outage>,
+    past_outages: Vec<Outage>,
+    /// How many times reading failed, even after logging in again.
+    errors: u64,
+}
+
+struct Outage {
+    started: DateTime<Utc>,
+    ended: Option<DateTime<Utc>>,
+    /// Why the device stopped answering.
+    cause: String,
+    /// How many times reconnecting failed so far.
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+impl Connection {
+    pub fn new(device: Device) -> Self {
+        Self {
+            device,
+            outage: None,
+            past_outages: Vec::new(),
+            errors: 0,
+        }
+    }
+
+    /// Momentary power consumption in Watts, or `None` while the device can't be reached. Logs in
+    /// again if reading fails, and keeps trying to with exponential backoff if that fails too.
+    pub async fn current_power(&mut self) -> Option<u64> {
+        match &self.outage {
+            Some(outage) if Instant::now() < outage.next_attempt => None,
+            Some(_) => match self.reconnect_and_read().await {
+                Ok(watts) => {
+                    if let Some(mut outage) = self.outage.take() {
+                        outage.ended = Some(Utc::now());
+                        self.past_outages.push(outage);
+                    }
+                    Some(watts)
+                }
+                Err(_) => {
+                    self.errors += 1;
+                    if let Some(outage) = &mut self.outage {
+                        outage.attempts += 1;
+                        outage.next_attempt = Instant::now() + backoff(outage.attempts);
+                    }
+                    None
+                }
+            },
+            None => match self.device.source.current_power().await {
+                Ok(watts) => Some(watts),
+                // Sessions expire every now and then, and logging in again is all it takes.
+                Err(error) => match self.reconnect_and_read().await {
+                    Ok(watts) => Some(watts),
+                    Err(_) => {
+                        self.errors += 1;
+                        self.outage = Some(Outage {
+                            started: Utc::now(),
+                            ended: None,
+                            cause: format!("{error:#}"),
+                            attempts: 0,
+                            next_attempt: Instant::now() + backoff(0),
+                        });
+                        None
+                    }
+                },
+            },
+        }
+    }
+
+    /// The device's own energy counters, or `None` if it can't be reached right now. Doesn't try
+    /// to reconnect, [`Self::current_power`] takes care of that.
+    pub async fn energy_usage(&self) -> Option<EnergyUsage> {
+        if self.outage.is_some() {
+            return None;
+        }
+        self.device.source.energy_usage().await.ok()
+    }
+
+    /// Describes the ongoing outage, if any.
+    pub fn status(&self) -> Option<String> {
+        let outage = self.outage.as_ref()?;
+        let retry_in = outage
+            .next_attempt
+            .saturating_duration_since(Instant::now());
+        Some(format!(
+            "unreachable since {}, reconnecting in {}s (attempt {}): {}",
+            outage.started.with_timezone(&Local).format("%H:%M:%S"),
+            retry_in.as_secs(),
+            outage.attempts + 1,
+            outage.cause
+        ))
+    }
+
+    async fn reconnect_and_read(&mut self) -> Result<u64> {
+        self.device.source.reconnect().await?;
+        self.device.source.current_power().await
+    }
+}
+
+/// What `serve`, `log` and `daemon` keep track of, see [`crate::polling::poll`].
+#[cfg(feature = "polling")]
+impl Connection {
+    /// What the device says about itself, or `None` if it can't be reached right now.
+    pub async fn device_info(&self) -> Option<crate::power_source::DeviceInfo> {
+        if self.outage.is_some() {
+            return None;
+        }
+        self.device.source.device_info().await.ok()
+    }
+
+    /// Whether the device answered the last time we tried to read from it.
+    pub fn is_up(&self) -> bool {
+        self.outage.is_none()
+    }
+
+    /// How many times reading from the device failed, even after logging in again.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+}
+
+/// Lists when each of the devices couldn't be reached, if ever.
+pub fn print_outages(connections: &[Connection]) {
+    let outages: Vec<(&str, &Outage)> = connections
+        .iter()
+        .flat_map(|connection| {
+            connection
+                .past_outages
+                .iter()
+                .chain(&connection.outage)
+                .map(|outage| (connection.device.name.as_str(), outage))
+        })
+        .collect();
+    if outages.is_empty() {
+        return;
+    }
+
+    eprintln!("outages:");
+    for (name, outage) in outages {
+        let started = outage.started.with_timezone(&Local);
+        let until = match outage.ended {
+            Some(ended) => {
+                let duration = (ended - outage.started).to_std().unwrap_or_default();
+                format!(
+                    "to {} ({})",
+                    ended.with_timezone(&Local).format("%H:%M:%S"),
+                    humantime::format_duration(Duration::from_secs(duration.as_secs()))
+                )
+            }
+            None => "until the end".to_string(),
+        };
+        eprintln!(
+            "  {name}: {} {until}, {}",
+            started.format("%Y-%m-%d %H:%M:%S"),
+            outage.cause
+        );
+    }
+}
+
+fn backoff(attempts: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts))
+        .min(MAX_BACKOFF)
+}
@@ -0,0 +1,162 @@
+use crate::{
+    power_source::{PowerSource, midnight, periods},
+    tariff::Tariff,
+};
+use anyhow::Result;
+use chrono::{Datelike, Days, Local, Months};
+use tapo::requests::EnergyDataInterval;
+
+/// The device returns hourly energy data for at most this many days at once.
+const MAX_HOURLY_DATA_DAYS: u64 = 8;
+
+/// How we label the part of the consumption not in any time-of-use period.
+const OUTSIDE_PERIODS: &str = "other times";
+
+/// Energy consumed over some period and what it cost.
+#[derive(Clone, Copy, Debug, Default)]
+struct Spend {
+    watt_hours: u64,
+    cost: f64,
+}
+
+impl Spend {
+    fn add(&mut self, other: Spend) {
+        self.watt_hours += other.watt_hours;
+        self.cost += other.cost;
+    }
+}
+
+/// Prints what the device's consumption cost today, so far this month and during the earlier
+/// months of this year, and projects the cost of the whole month. Uses the device's own energy
+/// history, hourly for time-of-use tariffs and daily otherwise.
+pub async fn report(device: &dyn PowerSource, tariff: &Tariff) -> Result<()> {
+    let now = Local::now();
+    let today = now.date_naive();
+    let month_start = today.with_day(1).expect("months have a first day");
+    let year_start = today.with_ordinal(1).expect("years have a first day");
+
+    let mut today_spend = Spend::default();
+    let mut month_spend = Spend::default();
+    // This month's spend in each of the tariff's periods, and outside of them.
+    let mut period_spends: Vec<(&str, Spend)> = tariff
+        .periods
+        .iter()
+        .map(|period| (period.name.as_str(), Spend::default()))
+        .chain([(OUTSIDE_PERIODS, Spend::default())])
+        .collect();
+    if tariff.is_time_of_use() {
+        // Price every hour of the month separately.
+        let mut chunk_start = month_start;
+        while chunk_start <= today {
+            let chunk_end = (chunk_start + Days::new(MAX_HOURLY_DATA_DAYS - 1)).min(today);
+            let interval = EnergyDataInterval::Hourly {
+                start_date: chunk_start,
+                end_date: chunk_end,
+            };
+            let hours = periods(&interval);
+            let data = device.energy_data(interval).await?;
+            for ((start, _), watt_hours) in hours.into_iter().zip(data) {
+                let spend = Spend {
+                    watt_hours,
+                    cost: tariff.cost(start, watt_hours as f64),
+                };
+                month_spend.add(spend);
+                let period = tariff
+                    .period_at(start)
+                    .map_or(OUTSIDE_PERIODS, |period| &period.name);
+                if let Some((_, period_spend)) =
+                    period_spends.iter_mut().find(|(name, _)| *name == period)
+                {
+                    period_spend.add(spend);
+                }
+                if start.date_naive() == today {
+                    today_spend.add(spend);
+                }
+            }
+            chunk_start = chunk_end + Days::new(1);
+        }
+    } else {
+        // Daily data only comes by the quarter.
+        let quarter_start = month_start
+            .with_month0(month_start.month0() / 3 * 3)
+            .expect("quarters start in valid months");
+        let interval = EnergyDataInterval::Daily {
+            start_date: quarter_start,
+        };
+        let days = periods(&interval);
+        let data = device.energy_data(interval).await?;
+        for ((start, _), watt_hours) in days.into_iter().zip(data) {
+            let spend = Spend {
+                watt_hours,
+                cost: tariff.price_per_kwh * watt_hours as f64 / 1000.0,
+            };
+            if start.date_naive() >= month_start {
+                month_spend.add(spend);
+            }
+            if start.date_naive() == today {
+                today_spend.add(spend);
+            }
+        }
+    }
+
+    let month_started = midnight(month_start);
+    let month_ends = midnight(month_start + Months::new(1));
+    let projected = month_spend.cost * (month_ends - month_started).as_seconds_f64()
+        / (now - month_started).as_seconds_f64();
+
+    println!(
+        "today: {} Wh, {}",
+        today_spend.watt_hours,
+        tariff.format(today_spend.cost)
+    );
+    println!(
+        "this month: {} Wh, {} (projected {} for all of {})",
+        month_spend.watt_hours,
+        tariff.format(month_spend.cost),
+        tariff.format(projected),
+        month_started.format("%B")
+    );
+    if tariff.is_time_of_use() {
+        for (period, spend) in &period_spends {
+            println!(
+                "  {period}: {} Wh, {}",
+                spend.watt_hours,
+                tariff.format(spend.cost)
+            );
+        }
+    }
+
+    if month_start == year_start {
+        return Ok(());
+    }
+
+    // The device only keeps monthly totals for earlier months, so we can't tell when exactly the
+    // energy was consumed. Use the average price of the current month as the best guess.
+    let price_per_kwh = if tariff.is_time_of_use() && month_spend.watt_hours > 0 {
+        let average = month_spend.cost * 1000.0 / month_spend.watt_hours as f64;
+        println!(
+            "earlier months, at this month's average of {}/kWh:",
+            tariff.format(average)
+        );
+        average
+    } else {
+        tariff.price_per_kwh
+    };
+    let interval = EnergyDataInterval::Monthly {
+        start_date: year_start,
+    };
+    let months = periods(&interval);
+    let data = device.energy_data(interval).await?;
+    for ((start, _), watt_hours) in months.into_iter().zip(data) {
+        if start.date_naive() >= month_start {
+            break;
+        }
+        println!(
+            "{}: {watt_hours} Wh, {}",
+            start.format("%Y-%m"),
+            tariff.format(price_per_kwh * watt_hours as f64 / 1000.0)
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use crate::config::Config;
+use anyhow::{Context, Result, bail};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+use tokio::process::Command;
+
+/// Where to get the Tapo account credentials from. The password can't be passed on the command
+/// line, where it would end up in the shell history and be visible to other users.
+#[derive(clap::Args, Debug)]
+pub struct CredentialArgs {
+    /// Tapo account username, i.e. email. Falls back to `username` in the config file.
+    #[arg(long, env = "TAPO_USERNAME")]
+    username: Option<String>,
+    /// Read the Tapo account password from the first line of this file. It must not be readable
+    /// by other users.
+    #[arg(long, value_name = "FILE", env = "TAPO_PASSWORD_FILE")]
+    password_file: Option<PathBuf>,
+    /// Run this shell command and use the first line it prints as the password, e.g.
+    /// `pass show tapo`.
+    #[arg(long, value_name = "COMMAND", conflicts_with = "password_file")]
+    password_command: Option<String>,
+}
+
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl CredentialArgs {
+    /// Looks for the password in order of `--password-command`, `--password-file` (or
+    /// `TAPO_PASSWORD_FILE`), `TAPO_PASSWORD`, and the same in the config file.
+    pub async fn resolve(&self, config: &Config) -> Result<Credentials> {
+        let username = self
+            .username
+            .clone()
+            .or_else(|| config.username.clone())
+            .context("No Tapo username, set TAPO_USERNAME or `username` in the config file")?;
+
+        let password = if let Some(command) = &self.password_command {
+            run_password_command(command).await?
+        } else if let Some(path) = &self.password_file {
+            read_password_file(path)?
+        } else if let Ok(password) = env::var("TAPO_PASSWORD") {
+            password
+        } else if let Some(command) = &config.password_command {
+            run_password_command(command).await?
+        } else if let Some(path) = &config.password_file {
+            read_password_file(path)?
+        } else if let Some(password) = &config.password {
+            if let Some(path) = &config.path {
+                ensure_private(path)?;
+            }
+            password.clone()
+        } else {
+            bail!(
+                "No Tapo password, use --password-file, --password-command, TAPO_PASSWORD or \
+                    the config file"
+            );
+        };
+
+        Ok(Credentials { username, password })
+    }
+}
+
+async fn run_password_command(command: &str) -> Result<String> {
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .output()
+        .await
+        .with_context(|| format!("Running password command {command:?}"))?;
+    if !output.status.success() {
+        bail!(
+            "Password command {command:?} failed with {}\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    first_line(&String::from_utf8(output.stdout).context("Password is not valid UTF-8")?)
+        .with_context(|| format!("Password command {command:?} printed nothing"))
+}
+
+fn read_password_file(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading password file {}", path.display()))?;
+    ensure_private(path)?;
+    first_line(&contents).with_context(|| format!("Password file {} is empty", path.display()))
+}
+
+fn first_line(text: &str) -> Option<String> {
+    text.lines()
+        .next()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+/// Refuses to use secrets from files everybody on the machine can read.
+#[cfg(unix)]
+fn ensure_private(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)
+        .with_context(|| format!("Reading permissions of {}", path.display()))?
+        .permissions()
+        .mode();
+    if mode & 0o004 != 0 {
+        bail!(
+            "{} contains a password but is readable by all users, restrict it with `chmod o-r`",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn ensure_private(_path: &Path) -> Result<()> {
+    Ok(())
+}
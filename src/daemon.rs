@@ -0,0 +1,407 @@
+use crate::{
+    analyze::parse_time,
+    connection::{Connection, print_outages},
+    polling::{DeviceState, poll},
+    power_source::{Device, DeviceInfo, EnergyUsage, PowerSource},
+};
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{Stream, stream};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tapo::requests::EnergyDataInterval;
+use tokio::{
+    net::TcpListener,
+    select,
+    signal::ctrl_c,
+    sync::broadcast::{self, error::RecvError},
+    time::sleep,
+};
+
+/// How many readings to hold on to for slow `/events` subscribers. They miss older ones.
+const EVENTS_CAPACITY: usize = 1000;
+/// How often to ask the daemon whether it has read a device again.
+const NEW_READING_POLL_PERIOD: Duration = Duration::from_millis(100);
+
+/// A device as served at `/devices` and `/devices/{id}`.
+#[derive(Serialize, Deserialize)]
+struct DeviceView {
+    /// The device's name, as given to `daemon`.
+    id: String,
+    /// Missing until the device first answered.
+    model: Option<String>,
+    nickname: Option<String>,
+    /// Whether the device answered the last time it was read.
+    up: bool,
+    /// How many times reading from the device failed.
+    errors: u64,
+    /// The latest reading, missing while the device can't be reached.
+    timestamp: Option<DateTime<Utc>>,
+    watts: Option<u64>,
+    /// Energy consumed since `daemon` started.
+    energy_wh: f64,
+    /// The device's own counters.
+    today_energy_wh: Option<u64>,
+    month_energy_wh: Option<u64>,
+    rssi_dbm: Option<i16>,
+    on_time_s: Option<u64>,
+}
+
+/// A reading as served at `/devices/{id}/power`, `/devices/{id}/history` and `/events`.
+#[derive(Clone, Serialize, Deserialize)]
+struct Reading {
+    device: String,
+    timestamp: DateTime<Utc>,
+    /// Missing while the device can't be reached.
+    watts: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Range {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+/// What the API serves from.
+#[derive(Clone)]
+struct Shared {
+    devices: Arc<Mutex<Vec<DeviceState>>>,
+    /// The recent readings of each device, oldest first.
+    history: Arc<Mutex<Vec<VecDeque<Reading>>>>,
+    events: broadcast::Sender<Reading>,
+}
+
+/// An error response, with the status and a message.
+type Failure = (StatusCode, String);
+
+/// Reads from a device through a running `daemon`, sharing its session with the device.
+struct DaemonSource {
+    client: Client,
+    url: Url,
+    id: String,
+    /// How long to wait for the daemon to read the device again.
+    timeout: Duration,
+    /// When the daemon took the reading we last returned.
+    last_reading: Mutex<Option<DateTime<Utc>>>,
+}
+
+/// Polls the devices until interrupted, serving what they read over HTTP at `listen` and keeping
+/// their readings of the last `keep` around.
+pub async fn daemon(
+    devices: Vec<Device>,
+    listen: SocketAddr,
+    keep: Duration,
+    every: Duration,
+) -> Result<()> {
+    let keep = TimeDelta::from_std(keep).context("The history is too long to keep")?;
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("Listening on {listen}"))?;
+    eprintln!(
+        "Serving {} on http://{}",
+        devices
+            .iter()
+            .map(|device| device.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        listener.local_addr()?
+    );
+
+    let mut connections: Vec<Connection> = devices.into_iter().map(Connection::new).collect();
+    let shared = Shared {
+        devices: Arc::new(Mutex::new(DeviceState::all(&connections))),
+        history: Arc::new(Mutex::new(vec![VecDeque::new(); connections.len()])),
+        events: broadcast::channel(EVENTS_CAPACITY).0,
+    };
+    let app = Router::new()
+        .route("/devices", get(list))
+        .route("/devices/{id}", get(device))
+        .route("/devices/{id}/power", get(power))
+        .route("/devices/{id}/history", get(history))
+        .route("/events", get(events))
+        .with_state(shared.clone());
+    let serving = async { axum::serve(listener, app).await.context("Serving the API") };
+
+    let record = |devices: &[DeviceState], timestamp: DateTime<Utc>| {
+        let mut history = shared.history.lock().unwrap();
+        for (device, readings) in devices.iter().zip(history.iter_mut()) {
+            let reading = Reading {
+                device: device.name.clone(),
+                timestamp,
+                watts: device.watts,
+            };
+            readings.push_back(reading.clone());
+            while readings
+                .front()
+                .is_some_and(|oldest| oldest.timestamp < timestamp - keep)
+            {
+                readings.pop_front();
+            }
+            // Fails only while nobody is subscribed.
+            let _ = shared.events.send(reading);
+        }
+        Ok(())
+    };
+
+    let result = select! {
+        result = serving => result,
+        result = poll(&mut connections, &shared.devices, every, record) => result,
+        _ = ctrl_c() => Ok(()),
+    };
+
+    print_outages(&connections);
+    result
+}
+
+async fn list(State(shared): State<Shared>) -> Json<Vec<DeviceView>> {
+    let devices = shared.devices.lock().unwrap();
+    Json(devices.iter().map(DeviceView::from).collect())
+}
+
+async fn device(
+    State(shared): State<Shared>,
+    Path(id): Path<String>,
+) -> Result<Json<DeviceView>, Failure> {
+    let devices = shared.devices.lock().unwrap();
+    let (_, device) = find(&devices, &id)?;
+    Ok(Json(DeviceView::from(device)))
+}
+
+async fn power(
+    State(shared): State<Shared>,
+    Path(id): Path<String>,
+) -> Result<Json<Reading>, Failure> {
+    let devices = shared.devices.lock().unwrap();
+    let (_, device) = find(&devices, &id)?;
+    let timestamp = device.timestamp.ok_or_else(|| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("{id:?} wasn't read yet"),
+        )
+    })?;
+    Ok(Json(Reading {
+        device: device.name.clone(),
+        timestamp,
+        watts: device.watts,
+    }))
+}
+
+async fn history(
+    State(shared): State<Shared>,
+    Path(id): Path<String>,
+    Query(range): Query<Range>,
+) -> Result<Json<Vec<Reading>>, Failure> {
+    let parse = |time: &Option<String>| {
+        time.as_deref()
+            .map(parse_time)
+            .transpose()
+            .map_err(|error| (StatusCode::BAD_REQUEST, format!("{error:#}")))
+    };
+    let (from, to) = (parse(&range.from)?, parse(&range.to)?);
+
+    let index = find(&shared.devices.lock().unwrap(), &id)?.0;
+    let history = shared.history.lock().unwrap();
+    Ok(Json(
+        history[index]
+            .iter()
+            .filter(|reading| from.is_none_or(|from| reading.timestamp >= from))
+            .filter(|reading| to.is_none_or(|to| reading.timestamp < to))
+            .cloned()
+            .collect(),
+    ))
+}
+
+/// Every reading of every device as it comes in.
+async fn events(
+    State(shared): State<Shared>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let readings = stream::unfold(shared.events.subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(reading) => {
+                    let event = Event::default().event("reading").json_data(&reading);
+                    return Some((event, receiver));
+                }
+                // Too slow to keep up, carry on with the latest.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(readings).keep_alive(KeepAlive::default())
+}
+
+fn find<'a>(devices: &'a [DeviceState], id: &str) -> Result<(usize, &'a DeviceState), Failure> {
+    devices
+        .iter()
+        .enumerate()
+        .find(|(_, device)| device.name == id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("No device {id:?}")))
+}
+
+impl From<&DeviceState> for DeviceView {
+    fn from(device: &DeviceState) -> Self {
+        let info = device.info.as_ref();
+        let usage = device.energy_usage.as_ref();
+        Self {
+            id: device.name.clone(),
+            model: info.map(|info| info.model.clone()),
+            nickname: info.map(|info| info.nickname.clone()),
+            up: device.up,
+            errors: device.errors,
+            timestamp: device.timestamp,
+            watts: device.watts,
+            energy_wh: device.energy_meter.watt_hours(),
+            today_energy_wh: usage.map(|usage| usage.today_energy),
+            month_energy_wh: usage.map(|usage| usage.month_energy),
+            rssi_dbm: info.and_then(|info| info.rssi),
+            on_time_s: info
+                .and_then(|info| info.on_time)
+                .map(|on_time| on_time.as_secs()),
+        }
+    }
+}
+
+/// The devices the daemon at `url` reads from, those named or all of them.
+pub async fn connect(url: &Url, names: &[String], timeout: Duration) -> Result<Vec<Device>> {
+    let client = Client::builder().timeout(timeout).build()?;
+    let devices: Vec<DeviceView> = fetch(&client, url, &["devices"])
+        .await
+        .with_context(|| format!("Reading from the daemon at {url}, is `daemon` running?"))?;
+    let ids: Vec<String> = devices.into_iter().map(|device| device.id).collect();
+    if ids.is_empty() {
+        bail!("The daemon at {url} reads from no devices");
+    }
+    let ids = if names.is_empty() {
+        ids
+    } else {
+        for name in names {
+            if !ids.contains(name) {
+                bail!(
+                    "The daemon at {url} doesn't read from {name:?}, only from {}",
+                    ids.join(", ")
+                );
+            }
+        }
+        names.to_vec()
+    };
+
+    Ok(ids
+        .into_iter()
+        .map(|id| Device {
+            name: id.clone(),
+            source: Box::new(DaemonSource {
+                client: client.clone(),
+                url: url.clone(),
+                id,
+                timeout,
+                last_reading: Mutex::new(None),
+            }),
+        })
+        .collect())
+}
+
+impl DaemonSource {
+    async fn device(&self) -> Result<DeviceView> {
+        fetch(&self.client, &self.url, &["devices", &self.id]).await
+    }
+}
+
+#[async_trait]
+impl PowerSource for DaemonSource {
+    /// Waits for the daemon to read the device again rather than returning the same reading
+    /// twice, which would skew averages when asked more often than the daemon reads.
+    async fn current_power(&self) -> Result<u64> {
+        let deadline = Instant::now() + self.timeout;
+        let reading = loop {
+            let reading: Reading =
+                fetch(&self.client, &self.url, &["devices", &self.id, "power"]).await?;
+            let last_reading = *self.last_reading.lock().unwrap();
+            if last_reading.is_none_or(|last_reading| reading.timestamp > last_reading) {
+                break reading;
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "The daemon hasn't read {} since {}, is it still running?",
+                    self.id,
+                    reading.timestamp
+                );
+            }
+            sleep(NEW_READING_POLL_PERIOD).await;
+        };
+        *self.last_reading.lock().unwrap() = Some(reading.timestamp);
+        reading
+            .watts
+            .with_context(|| format!("The daemon can't reach {}", self.id))
+    }
+
+    async fn energy_usage(&self) -> Result<EnergyUsage> {
+        let device = self.device().await?;
+        match (device.today_energy_wh, device.month_energy_wh) {
+            (Some(today_energy), Some(month_energy)) => Ok(EnergyUsage {
+                today_energy,
+                month_energy,
+            }),
+            _ => bail!(
+                "The daemon hasn't read the energy counters of {} yet",
+                self.id
+            ),
+        }
+    }
+
+    async fn device_info(&self) -> Result<DeviceInfo> {
+        let device = self.device().await?;
+        match (device.model, device.nickname) {
+            (Some(model), Some(nickname)) => Ok(DeviceInfo {
+                model,
+                nickname,
+                rssi: device.rssi_dbm,
+                on_time: device.on_time_s.map(Duration::from_secs),
+            }),
+            _ => bail!("The daemon hasn't reached {} yet", self.id),
+        }
+    }
+
+    async fn energy_data(&self, _interval: EnergyDataInterval) -> Result<Vec<u64>> {
+        bail!(
+            "The daemon doesn't serve the energy history of the devices, connect to them directly"
+        )
+    }
+
+    /// The daemon reconnects to the device by itself.
+    async fn reconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Gets the JSON at the path made of `segments` below `base`.
+async fn fetch<T: DeserializeOwned>(client: &Client, base: &Url, segments: &[&str]) -> Result<T> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|()| anyhow!("Not a URL to get paths below: {base}"))?
+        .pop_if_empty()
+        .extend(segments);
+    let response = client.get(url).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!(
+            "{status} {}",
+            response.text().await.unwrap_or_default().trim()
+        );
+    }
+    Ok(response.json().await?)
+}
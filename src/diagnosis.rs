@@ -0,0 +1,95 @@
+use anyhow::Error;
+use std::io::ErrorKind;
+use tapo::TapoResponseError;
+
+/// The ways talking to a device commonly goes wrong, which we can tell apart and suggest a fix
+/// for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The device didn't answer in time.
+    Timeout,
+    /// No route to the device's network.
+    Unreachable,
+    /// Something is at the address, but it doesn't accept connections on the API port.
+    Refused,
+    /// The device doesn't accept the Tapo account credentials.
+    AuthenticationFailed,
+    /// The device answered in a way we don't understand.
+    ProtocolMismatch,
+}
+
+impl Failure {
+    /// Makes sense of an error from talking to a device, if we can.
+    pub fn classify(error: &Error) -> Option<Self> {
+        for cause in error.chain() {
+            if let Some(error) = cause.downcast_ref::<tapo::Error>() {
+                match error {
+                    tapo::Error::Tapo(TapoResponseError::InvalidCredentials) => {
+                        return Some(Failure::AuthenticationFailed);
+                    }
+                    tapo::Error::Tapo(
+                        TapoResponseError::InvalidRequest
+                        | TapoResponseError::InvalidResponse
+                        | TapoResponseError::MalformedRequest
+                        | TapoResponseError::InvalidPublicKey
+                        | TapoResponseError::Unknown(_),
+                    )
+                    | tapo::Error::Serde(_) => return Some(Failure::ProtocolMismatch),
+                    tapo::Error::Http(error) if error.is_timeout() => {
+                        return Some(Failure::Timeout);
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(error) = cause.downcast_ref::<std::io::Error>() {
+                match error.kind() {
+                    ErrorKind::TimedOut => return Some(Failure::Timeout),
+                    ErrorKind::ConnectionRefused => return Some(Failure::Refused),
+                    ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => {
+                        return Some(Failure::Unreachable);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        None
+    }
+
+    /// What went wrong talking to the device at `address`, and what to do about it.
+    pub fn explain(&self, address: &str) -> String {
+        match self {
+            Failure::Timeout => format!(
+                "{address} didn't answer in time. Check that the plug is powered and connected \
+                    to the network, or allow it more time with --timeout."
+            ),
+            Failure::Unreachable => format!(
+                "{address} can't be reached. Check that this machine is on the same network as \
+                    the plug, and not e.g. on a guest network or a VPN."
+            ),
+            Failure::Refused => format!(
+                "{address} refused the connection, it doesn't look like a Tapo plug. The plug \
+                    may have a new IP address, `discover` finds it. Kasa plugs like the KP115 \
+                    speak a different protocol, which isn't supported."
+            ),
+            Failure::AuthenticationFailed => format!(
+                "{address} rejected the credentials. Use the email and password of the Tapo \
+                    account the plug is paired with. If you changed the password recently, \
+                    unplug the plug and plug it back in."
+            ),
+            Failure::ProtocolMismatch => format!(
+                "{address} answered in a way we don't understand, e.g. because a firmware update \
+                    changed its protocol. Enable \"Third-Party Compatibility\" in the Tapo app \
+                    under Me > Third-Party Services, or update tapo-power-monitor."
+            ),
+        }
+    }
+}
+
+/// Adds an explanation to an error from talking to the device at `address`, if we have one.
+pub fn diagnose(error: Error, address: &str) -> Error {
+    match Failure::classify(&error) {
+        Some(failure) => error.context(failure.explain(address)),
+        None => error,
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{config::Model, credentials::Credentials};
+use anyhow::{Context, Result};
+use futures::future::join_all;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tapo::ApiClient;
+use tokio::{
+    net::UdpSocket,
+    time::{Instant, timeout_at},
+};
+
+/// Tapo devices listen for discovery requests on this UDP port.
+pub const DISCOVERY_PORT: u16 = 20002;
+
+/// Devices encrypt a session key for a protocol we don't use with this. It just has to be a valid
+/// key, we never need the private half.
+const RSA_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDIHvztK3Kt2JvkqJbDKRMP/0V+
+5m0ynucfkqcq4rAySQ9rbzMduHgE85bJqlGlVenF6zMPpOzkTKp69GeqWPsRvw1n
+Fx+0S0/A70sYn4yuan2pFVUtcCvjNrBr1kz7XN/ssF1Iydx8xZ2wLlIDD8/R++QO
+3MI+ZnVbl07PVV0lcwIDAQAB
+-----END PUBLIC KEY-----
+";
+
+/// Requests and responses start with a header of this size, followed by JSON.
+const HEADER_LEN: usize = 16;
+
+/// A device that answered the discovery request.
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+    pub ip: IpAddr,
+    /// Port of the device's local API.
+    pub port: u16,
+    pub mac: String,
+    pub model: String,
+}
+
+#[derive(Deserialize)]
+struct DiscoveryResponse {
+    result: DiscoveryResult,
+}
+
+#[derive(Deserialize)]
+struct DiscoveryResult {
+    ip: Option<IpAddr>,
+    mac: String,
+    device_model: String,
+    mgt_encrypt_schm: Option<EncryptionScheme>,
+}
+
+#[derive(Deserialize)]
+struct EncryptionScheme {
+    http_port: Option<u16>,
+}
+
+impl DiscoveredDevice {
+    /// Whether we know how to read power consumption from this model.
+    pub fn supports_energy_monitoring(&self) -> bool {
+        Model::from_device_model(&self.model).is_some()
+    }
+
+    /// How to pass this device on the command line.
+    pub fn address(&self) -> String {
+        match self.port {
+            80 => self.ip.to_string(),
+            port => SocketAddr::new(self.ip, port).to_string(),
+        }
+    }
+}
+
+/// Sends a discovery request to `target`, typically the broadcast address, and collects answers
+/// until `wait` has passed.
+pub async fn discover(target: SocketAddr, wait: Duration) -> Result<Vec<DiscoveredDevice>> {
+    let bind_address: SocketAddr = match target {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+        SocketAddr::V6(_) => "[::]:0".parse()?,
+    };
+    let socket = UdpSocket::bind(bind_address)
+        .await
+        .context("Binding discovery socket")?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_to(&request(), target)
+        .await
+        .with_context(|| format!("Sending discovery request to {target}"))?;
+
+    // Devices may answer more than once, keep one answer per address.
+    let mut devices = BTreeMap::new();
+    let deadline = Instant::now() + wait;
+    let mut buffer = vec![0; 4096];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (len, source) = received?;
+        let Some(response) = buffer[..len]
+            .get(HEADER_LEN..)
+            .and_then(|body| serde_json::from_slice::<DiscoveryResponse>(body).ok())
+        else {
+            eprintln!("warning: ignoring malformed discovery response from {source}");
+            continue;
+        };
+
+        let result = response.result;
+        let ip = result.ip.unwrap_or(source.ip());
+        let port = result
+            .mgt_encrypt_schm
+            .and_then(|scheme| scheme.http_port)
+            .unwrap_or(80);
+        devices.insert(
+            ip,
+            DiscoveredDevice {
+                ip,
+                port,
+                mac: result.mac,
+                model: result.device_model,
+            },
+        );
+    }
+
+    Ok(devices.into_values().collect())
+}
+
+/// Asks each of the devices for its nickname, which it only tells after logging in. `None` for
+/// devices that don't answer within `timeout` or don't accept the credentials.
+pub async fn nicknames(
+    devices: &[DiscoveredDevice],
+    credentials: &Credentials,
+    timeout: Duration,
+) -> Vec<Option<String>> {
+    join_all(devices.iter().map(|device| async move {
+        let handler = ApiClient::new(&credentials.username, &credentials.password)
+            .with_timeout(timeout)
+            .generic_device(device.address())
+            .await
+            .ok()?;
+        Some(handler.get_device_info().await.ok()?.nickname)
+    }))
+    .await
+}
+
+/// Prints the discovered devices as a table. Looks up their nicknames if we have credentials.
+pub async fn print(
+    devices: &[DiscoveredDevice],
+    credentials: Option<&Credentials>,
+    timeout: Duration,
+) {
+    let nicknames = match credentials {
+        Some(credentials) => nicknames(devices, credentials, timeout).await,
+        None => vec![None; devices.len()],
+    };
+
+    let rows: Vec<[String; 5]> = devices
+        .iter()
+        .zip(nicknames)
+        .map(|(device, nickname)| {
+            [
+                device.address(),
+                device.mac.clone(),
+                device.model.clone(),
+                if device.supports_energy_monitoring() {
+                    "yes"
+                } else {
+                    "no"
+                }
+                .to_string(),
+                nickname.unwrap_or_else(|| "?".to_string()),
+            ]
+        })
+        .collect();
+    let header = ["IP", "MAC", "MODEL", "ENERGY", "NICKNAME"].map(str::to_string);
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .chain([&header])
+                .map(|row| row[column].len())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    for row in [&header].into_iter().chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// A discovery request as sent by the Tapo app: a header with a checksum, followed by JSON.
+fn request() -> Vec<u8> {
+    const VERSION: u8 = 2;
+    const MESSAGE_TYPE: u8 = 0;
+    const OP_CODE_PROBE: u16 = 1;
+    const FLAGS: u8 = 17;
+    // Replaced by the checksum of the whole request, including this.
+    const INITIAL_CRC: u32 = 0x5A6B_7C8D;
+
+    let body = json!({ "params": { "rsa_key": RSA_PUBLIC_KEY } }).to_string();
+    let mut request = Vec::with_capacity(HEADER_LEN + body.len());
+    request.push(VERSION);
+    request.push(MESSAGE_TYPE);
+    request.extend(OP_CODE_PROBE.to_be_bytes());
+    request.extend((body.len() as u16).to_be_bytes());
+    request.push(FLAGS);
+    request.push(0);
+    request.extend(rand::random::<u32>().to_be_bytes());
+    request.extend(INITIAL_CRC.to_be_bytes());
+    request.extend(body.as_bytes());
+
+    let crc = crc32fast::hash(&request);
+    request[12..HEADER_LEN].copy_from_slice(&crc.to_be_bytes());
+    request
+}
@@ -0,0 +1,155 @@
+use crate::{
+    Args,
+    config::{Config, Model},
+    diagnosis::Failure,
+};
+use anyhow::{Error, anyhow};
+use console::style;
+use std::{fmt::Display, net::SocketAddr};
+use tapo::ApiClient;
+use tokio::{net::TcpStream, time::timeout};
+
+/// Checks step by step that we can read from the devices, printing what we find along the way and
+/// how to fix what is wrong. Returns whether all is well.
+pub async fn run(args: &Args, config: &Config) -> bool {
+    match &config.path {
+        Some(path) => pass("config", path.display()),
+        None => pass("config", "none, using the defaults"),
+    }
+    if args.simulate.is_some() {
+        fail(
+            "device",
+            &anyhow!("There is nothing to check about a simulated plug"),
+            None,
+        );
+        return false;
+    }
+
+    let credentials = match args.credentials.resolve(config).await {
+        Ok(credentials) => {
+            pass(
+                "credentials",
+                format!("Tapo account {}", credentials.username),
+            );
+            credentials
+        }
+        Err(error) => {
+            fail("credentials", &error, None);
+            return false;
+        }
+    };
+
+    let specs = match args.find_devices(config, &credentials).await {
+        Ok(specs) => specs,
+        Err(error) => {
+            fail("device", &error, None);
+            return false;
+        }
+    };
+
+    let mut healthy = true;
+    for spec in specs {
+        let address = spec.address.to_api_address();
+        if spec.name == address {
+            pass("device", &address);
+        } else {
+            pass("device", format!("{} at {address}", spec.name));
+        }
+
+        let socket_address = SocketAddr::new(spec.address.ip, spec.address.port.unwrap_or(80));
+        match timeout(args.timeout, TcpStream::connect(socket_address)).await {
+            Ok(Ok(_)) => pass("network", format!("{socket_address} accepts connections")),
+            Ok(Err(error)) => {
+                fail("network", &error.into(), Some(&address));
+                healthy = false;
+                continue;
+            }
+            Err(_) => {
+                println!(
+                    "{} network: no answer within {}",
+                    style("✗").red(),
+                    humantime::format_duration(args.timeout)
+                );
+                println!("  {}", Failure::Timeout.explain(&address));
+                healthy = false;
+                continue;
+            }
+        }
+
+        let client =
+            ApiClient::new(&credentials.username, &credentials.password).with_timeout(args.timeout);
+        let handler = match client.clone().generic_device(address.clone()).await {
+            Ok(handler) => {
+                pass("login", "the device accepts the credentials");
+                handler
+            }
+            Err(error) => {
+                fail("login", &error.into(), Some(&address));
+                healthy = false;
+                continue;
+            }
+        };
+
+        let model = match handler.get_device_info().await {
+            Ok(info) => match Model::detect(&info.model) {
+                Ok(model) => {
+                    pass(
+                        "model",
+                        format!(
+                            "{} {:?}, firmware {}, Wi-Fi signal {} dBm",
+                            info.model, info.nickname, info.fw_ver, info.rssi
+                        ),
+                    );
+                    if spec.model != Model::Auto && spec.model != model {
+                        println!(
+                            "  It is configured as a {:?}, which doesn't matter for reading it.",
+                            spec.model
+                        );
+                    }
+                    model
+                }
+                Err(error) => {
+                    fail("model", &error, None);
+                    healthy = false;
+                    continue;
+                }
+            },
+            Err(error) => {
+                fail("model", &error.into(), Some(&address));
+                healthy = false;
+                continue;
+            }
+        };
+
+        let reading = async {
+            let device = match model {
+                Model::P110 => client.p110(address.clone()).await?,
+                Model::P115 | Model::Auto => client.p115(address.clone()).await?,
+            };
+            anyhow::Ok(device.get_current_power().await?.current_power)
+        };
+        match reading.await {
+            Ok(watts) => pass("reading", format!("{watts} W")),
+            Err(error) => {
+                fail("reading", &error, Some(&address));
+                healthy = false;
+            }
+        }
+    }
+
+    healthy
+}
+
+fn pass(step: &str, detail: impl Display) {
+    println!("{} {step}: {detail}", style("✓").green());
+}
+
+/// Prints the error, and what to do about it if we know.
+fn fail(step: &str, error: &Error, address: Option<&str>) {
+    println!("{} {step}: {error:#}", style("✗").red());
+    if let Some(address) = address
+        && let Some(failure) = Failure::classify(error)
+    {
+        println!("  {}", failure.explain(address));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{measure::Sample, tariff::Tariff};
+
+/// The device counts energy in whole Watt hours, so differences up to that are to be expected.
+const DEVICE_COUNTER_RESOLUTION_WH: f64 = 1.0;
+
+/// Integrates power over time with the trapezoidal rule, using the actual sample timestamps
+/// rather than assuming they are evenly spaced. Optionally also adds up what the energy cost.
+#[derive(Default, Debug)]
+pub struct EnergyMeter {
+    last: Option<Sample>,
+    /// In Joules (Watt seconds).
+    energy: f64,
+    tariff: Option<Tariff>,
+    cost: f64,
+}
+
+impl EnergyMeter {
+    pub fn new(tariff: Option<Tariff>) -> Self {
+        Self {
+            tariff,
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, sample: &Sample) {
+        if let Some(last) = &self.last {
+            let elapsed = (sample.timestamp - last.timestamp).as_seconds_f64();
+            let energy = (last.watts + sample.watts) as f64 / 2.0 * elapsed;
+            self.energy += energy;
+            if let Some(tariff) = &self.tariff {
+                self.cost += tariff.cost(last.timestamp, joules_to_watt_hours(energy));
+            }
+        }
+        self.last = Some(sample.clone());
+    }
+
+    pub fn joules(&self) -> f64 {
+        self.energy
+    }
+
+    /// What the energy so far cost, if we know the tariff.
+    pub fn cost(&self) -> Option<f64> {
+        self.tariff.as_ref().map(|_| self.cost)
+    }
+
+    pub fn watt_hours(&self) -> f64 {
+        joules_to_watt_hours(self.energy)
+    }
+}
+
+/// Energy consumed over the span of the given samples.
+pub fn integrate(samples: &[Sample], tariff: Option<Tariff>) -> EnergyMeter {
+    let mut meter = EnergyMeter::new(tariff);
+    samples.iter().for_each(|sample| meter.add(sample));
+    meter
+}
+
+pub fn joules_to_watt_hours(joules: f64) -> f64 {
+    joules / 3600.0
+}
+
+/// How much the device's own energy counter increased between two readings of it, unless it was
+/// reset in the meantime, e.g. at midnight.
+pub fn counter_increase(before: u64, after: u64) -> Option<u64> {
+    after.checked_sub(before)
+}
+
+/// Whether the energy we integrated disagrees with the device's own counter by more than the
+/// counter's resolution.
+pub fn is_discrepancy(integrated_wh: f64, device_counter_wh: u64) -> bool {
+    (integrated_wh - device_counter_wh as f64).abs() > DEVICE_COUNTER_RESOLUTION_WH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, TimeDelta};
+
+    fn sample(seconds: f64, watts: u64) -> Sample {
+        Sample {
+            timestamp: DateTime::UNIX_EPOCH + TimeDelta::milliseconds((seconds * 1000.0) as i64),
+            watts,
+        }
+    }
+
+    #[test]
+    fn integrates_trapezoids_over_the_actual_timestamps() {
+        let meter = integrate(
+            &[
+                sample(0.0, 100),
+                sample(1.0, 200),
+                sample(4.0, 200),
+                sample(4.5, 0),
+            ],
+            None,
+        );
+        // 150 J over the first second, 600 J over the next three and 50 J over the last half.
+        assert_eq!(meter.joules(), 800.0);
+        assert_eq!(meter.watt_hours(), 800.0 / 3600.0);
+    }
+
+    #[test]
+    fn needs_two_samples() {
+        assert_eq!(integrate(&[], None).joules(), 0.0);
+        assert_eq!(integrate(&[sample(0.0, 100)], None).joules(), 0.0);
+    }
+
+    #[test]
+    fn zero_length_intervals_add_nothing() {
+        let meter = integrate(
+            &[sample(0.0, 100), sample(0.0, 300), sample(1.0, 100)],
+            None,
+        );
+        // Only the interval from the second sample on counts.
+        assert_eq!(meter.joules(), 200.0);
+    }
+
+    #[test]
+    fn costs_only_with_a_tariff() {
+        let samples = [sample(0.0, 1000), sample(3600.0, 1000)];
+        assert_eq!(integrate(&samples, None).cost(), None);
+        let tariff = Tariff::flat(0.25, "EUR".to_string());
+        assert_eq!(integrate(&samples, Some(tariff)).cost(), Some(0.25));
+    }
+
+    #[test]
+    fn counter_increases() {
+        assert_eq!(counter_increase(10, 10), Some(0));
+        assert_eq!(counter_increase(10, 13), Some(3));
+    }
+
+    #[test]
+    fn counter_decrease_is_a_reset() {
+        // E.g. the daily counter going back to zero at midnight.
+        assert_eq!(counter_increase(13, 0), None);
+        assert_eq!(counter_increase(13, 12), None);
+    }
+
+    #[test]
+    fn discrepancies_beyond_the_counter_resolution() {
+        assert!(!is_discrepancy(0.0, 0));
+        assert!(!is_discrepancy(0.4, 1));
+        // The counter only counts whole Watt hours.
+        assert!(!is_discrepancy(1.99, 1));
+        assert!(!is_discrepancy(2.0, 1));
+        assert!(is_discrepancy(2.01, 1));
+        assert!(is_discrepancy(0.5, 2));
+    }
+}
@@ -0,0 +1,483 @@
+use crate::{
+    connection::{Connection, print_outages},
+    measure::{OutputFormat, toml_list},
+    polling::{DeviceState, poll},
+    power_source::{Device, DeviceInfo},
+};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use clap::ValueEnum;
+use rusqlite::{Connection as Database, OpenFlags, params};
+use serde::Serialize;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::{select, signal::ctrl_c};
+
+const MINUTE: i64 = 60_000;
+const HOUR: i64 = 60 * MINUTE;
+/// Bumped whenever the schema changes.
+const SCHEMA_VERSION: i64 = 1;
+
+/// Readings of all devices, as every single sample (`samples`), per-minute (`minutes`) and
+/// per-hour (`hours`) aggregates. Timestamps are milliseconds since the epoch, aggregates start at
+/// the beginning of their minute or hour.
+const SCHEMA: &str = "
+    CREATE TABLE devices (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        model TEXT,
+        nickname TEXT
+    );
+    CREATE TABLE samples (
+        device INTEGER NOT NULL REFERENCES devices (id),
+        timestamp INTEGER NOT NULL,
+        -- NULL while the device couldn't be reached.
+        watts INTEGER,
+        PRIMARY KEY (device, timestamp)
+    ) WITHOUT ROWID;
+    CREATE TABLE minutes (
+        device INTEGER NOT NULL REFERENCES devices (id),
+        start INTEGER NOT NULL,
+        readings INTEGER NOT NULL,
+        missing INTEGER NOT NULL,
+        -- NULL if there were no readings.
+        mean_w REAL,
+        min_w INTEGER,
+        max_w INTEGER,
+        PRIMARY KEY (device, start)
+    ) WITHOUT ROWID;
+    CREATE TABLE hours (
+        device INTEGER NOT NULL REFERENCES devices (id),
+        start INTEGER NOT NULL,
+        readings INTEGER NOT NULL,
+        missing INTEGER NOT NULL,
+        mean_w REAL,
+        min_w INTEGER,
+        max_w INTEGER,
+        PRIMARY KEY (device, start)
+    ) WITHOUT ROWID;
+";
+
+/// The database `log` writes to and `query` reads from.
+pub struct Store {
+    path: PathBuf,
+    db: Database,
+}
+
+/// How long to keep readings at each resolution. Per-hour aggregates are kept forever.
+pub struct Retention {
+    pub raw: Duration,
+    pub minutes: Duration,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// Every single reading.
+    Raw,
+    /// Per-minute aggregates.
+    Minute,
+    /// Per-hour aggregates.
+    Hour,
+}
+
+/// A reading, or the aggregate of the readings over a minute or an hour.
+#[derive(Serialize)]
+pub struct Row {
+    pub device: String,
+    /// When the reading was taken, or the start of the minute or hour.
+    pub timestamp: DateTime<Utc>,
+    /// Missing if the device couldn't be reached.
+    pub mean_w: Option<f64>,
+    pub min_w: Option<u64>,
+    pub max_w: Option<u64>,
+    /// How many readings were taken, and how many times the device couldn't be reached.
+    pub readings: u64,
+    pub missing: u64,
+}
+
+impl Store {
+    /// Opens the database to write to, creating it if needed.
+    pub fn create(path: &Path) -> Result<Self> {
+        let db = Database::open(path).with_context(|| format!("Opening {}", path.display()))?;
+        let mut store = Self {
+            path: path.to_path_buf(),
+            db,
+        };
+        store
+            .migrate()
+            .with_context(|| format!("Opening {}", store.path.display()))?;
+        Ok(store)
+    }
+
+    /// Opens an existing database to read from, leaving it as is.
+    pub fn open(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            bail!(
+                "{} doesn't exist, `log` creates it when it starts logging",
+                path.display()
+            );
+        }
+        let db = Database::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .with_context(|| format!("Opening {}", path.display()))?;
+        db.busy_timeout(Duration::from_secs(5))?;
+        let version: i64 = db
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .with_context(|| format!("Opening {}", path.display()))?;
+        match version {
+            SCHEMA_VERSION => {}
+            0 => bail!("{} wasn't written by `log`", path.display()),
+            _ => bail!(
+                "{} was written by a newer version of this tool (schema version {version})",
+                path.display()
+            ),
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            db,
+        })
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        // Lets `query` read while `log` writes.
+        self.db
+            .pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        self.db.busy_timeout(Duration::from_secs(5))?;
+        let version: i64 = self
+            .db
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+        match version {
+            0 => {
+                let transaction = self.db.transaction()?;
+                transaction.execute_batch(SCHEMA)?;
+                transaction.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+                transaction.commit()?;
+            }
+            SCHEMA_VERSION => {}
+            _ => bail!("It was written by a newer version of this tool (schema version {version})"),
+        }
+        Ok(())
+    }
+
+    /// The IDs of the devices with these names, adding those not seen before.
+    fn add_devices(&mut self, names: &[&str]) -> Result<Vec<i64>> {
+        names
+            .iter()
+            .map(|name| {
+                self.db.execute(
+                    "INSERT OR IGNORE INTO devices (name) VALUES (?1)",
+                    params![name],
+                )?;
+                Ok(self.db.query_row(
+                    "SELECT id FROM devices WHERE name = ?1",
+                    params![name],
+                    |row| row.get(0),
+                )?)
+            })
+            .collect()
+    }
+
+    fn set_info(&self, device: i64, info: &DeviceInfo) -> Result<()> {
+        self.db.execute(
+            "UPDATE devices SET model = ?2, nickname = ?3 WHERE id = ?1",
+            params![device, info.model, info.nickname],
+        )?;
+        Ok(())
+    }
+
+    /// Stores the readings of the devices taken at the same time.
+    fn insert(
+        &mut self,
+        devices: &[i64],
+        timestamp: DateTime<Utc>,
+        readings: &[Option<u64>],
+    ) -> Result<()> {
+        let transaction = self.db.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO samples (device, timestamp, watts) VALUES (?1, ?2, ?3)",
+            )?;
+            for (device, watts) in devices.iter().zip(readings) {
+                insert.execute(params![device, timestamp.timestamp_millis(), watts])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Aggregates the readings of every complete minute and hour not aggregated yet, and deletes
+    /// what is older than we keep.
+    fn roll_up(&mut self, now: DateTime<Utc>, retention: &Retention) -> Result<()> {
+        let now = now.timestamp_millis();
+        let minute_end = now - now.rem_euclid(MINUTE);
+        let hour_end = now - now.rem_euclid(HOUR);
+        let transaction = self.db.transaction()?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO minutes
+                SELECT device, timestamp - timestamp % ?3, count(watts), count(*) - count(watts),
+                    avg(watts), min(watts), max(watts)
+                FROM samples
+                WHERE timestamp >= ?1 AND timestamp < ?2
+                GROUP BY 1, 2",
+            params![
+                transaction.query_row(
+                    "SELECT coalesce(max(start) + ?1, 0) FROM minutes",
+                    params![MINUTE],
+                    |row| row.get::<_, i64>(0),
+                )?,
+                minute_end,
+                MINUTE
+            ],
+        )?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO hours
+                SELECT device, start - start % ?3, sum(readings), sum(missing),
+                    sum(mean_w * readings) / sum(readings), min(min_w), max(max_w)
+                FROM minutes
+                WHERE start >= ?1 AND start < ?2
+                GROUP BY 1, 2",
+            params![
+                transaction.query_row(
+                    "SELECT coalesce(max(start) + ?1, 0) FROM hours",
+                    params![HOUR],
+                    |row| row.get::<_, i64>(0),
+                )?,
+                hour_end,
+                HOUR
+            ],
+        )?;
+        // Only what has been aggregated.
+        transaction.execute(
+            "DELETE FROM samples WHERE timestamp < ?1",
+            params![now.saturating_sub(millis(retention.raw)).min(minute_end)],
+        )?;
+        transaction.execute(
+            "DELETE FROM minutes WHERE start < ?1",
+            params![now.saturating_sub(millis(retention.minutes)).min(hour_end)],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// The finest resolution suitable for looking at readings from `from` to `to`. Fine
+    /// resolutions are skipped when they were not kept as far back as `from`.
+    pub fn pick_resolution(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Resolution> {
+        let span = to - from;
+        let candidates: &[Resolution] = if span <= TimeDelta::hours(6) {
+            &[Resolution::Raw, Resolution::Minute, Resolution::Hour]
+        } else if span <= TimeDelta::days(7) {
+            &[Resolution::Minute, Resolution::Hour]
+        } else {
+            &[Resolution::Hour]
+        };
+
+        for &resolution in candidates {
+            if self
+                .oldest(resolution)?
+                .is_some_and(|oldest| oldest <= from.timestamp_millis())
+            {
+                return Ok(resolution);
+            }
+        }
+        // Otherwise whichever goes back furthest, the coarsest if several do.
+        let mut best = (candidates[0], None);
+        for resolution in [Resolution::Hour, Resolution::Minute, Resolution::Raw] {
+            if let Some(oldest) = self.oldest(resolution)?
+                && best.1.is_none_or(|best| oldest < best)
+            {
+                best = (resolution, Some(oldest));
+            }
+        }
+        Ok(best.0)
+    }
+
+    /// When the oldest reading kept at this resolution was taken.
+    fn oldest(&self, resolution: Resolution) -> Result<Option<i64>> {
+        let (table, column) = resolution.table();
+        Ok(self
+            .db
+            .query_row(&format!("SELECT min({column}) FROM {table}"), [], |row| {
+                row.get(0)
+            })?)
+    }
+
+    /// The readings from `from` to `to` of the devices with these names, or of all of them.
+    pub fn query(
+        &self,
+        devices: &[String],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        resolution: Resolution,
+    ) -> Result<Vec<Row>> {
+        let (table, column) = resolution.table();
+        let values = match resolution {
+            Resolution::Raw => "watts, watts, watts, watts IS NOT NULL, watts IS NULL",
+            Resolution::Minute | Resolution::Hour => "mean_w, min_w, max_w, readings, missing",
+        };
+        // Including the aggregates that started before `from`.
+        let length = match resolution {
+            Resolution::Raw => 1,
+            Resolution::Minute => MINUTE,
+            Resolution::Hour => HOUR,
+        };
+        let mut statement = self.db.prepare(&format!(
+            "SELECT devices.name, {column}, {values}
+                FROM {table} JOIN devices ON devices.id = {table}.device
+                WHERE {column} > ?1 - ?3 AND {column} < ?2
+                    AND (json_array_length(?4) = 0 OR devices.name IN (SELECT value FROM json_each(?4)))
+                ORDER BY {column}, devices.name"
+        ))?;
+        let rows = statement.query_map(
+            params![
+                from.timestamp_millis(),
+                to.timestamp_millis(),
+                length,
+                serde_json::to_string(devices)?
+            ],
+            |row| {
+                Ok(Row {
+                    device: row.get(0)?,
+                    timestamp: DateTime::from_timestamp_millis(row.get(1)?).unwrap_or_default(),
+                    mean_w: row.get(2)?,
+                    min_w: row.get(3)?,
+                    max_w: row.get(4)?,
+                    readings: row.get(5)?,
+                    missing: row.get(6)?,
+                })
+            },
+        )?;
+        rows.collect::<rusqlite::Result<_>>()
+            .with_context(|| format!("Reading {}", self.path.display()))
+    }
+}
+
+impl Resolution {
+    /// Where the readings are, and the column of their timestamps.
+    fn table(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::Raw => ("samples", "timestamp"),
+            Self::Minute => ("minutes", "start"),
+            Self::Hour => ("hours", "start"),
+        }
+    }
+}
+
+/// Reads from all devices until interrupted, storing every reading in the database at `path`.
+pub async fn log(
+    devices: Vec<Device>,
+    path: &Path,
+    retention: Retention,
+    every: Duration,
+) -> Result<()> {
+    let mut store = Store::create(path)?;
+    let names: Vec<&str> = devices.iter().map(|device| device.name.as_str()).collect();
+    let ids = store
+        .add_devices(&names)
+        .with_context(|| format!("Writing {}", path.display()))?;
+    // Catch up with what was logged before.
+    store
+        .roll_up(Utc::now(), &retention)
+        .with_context(|| format!("Writing {}", path.display()))?;
+    eprintln!("Logging to {}", path.display());
+
+    let mut connections: Vec<Connection> = devices.into_iter().map(Connection::new).collect();
+    let states = Mutex::new(DeviceState::all(&connections));
+    // What we last stored of each device's info, to store it again only when it changes.
+    let mut infos: Vec<Option<(String, String)>> = vec![None; ids.len()];
+    let mut minute = None;
+    let store_readings = |states: &[DeviceState], timestamp: DateTime<Utc>| {
+        let readings: Vec<Option<u64>> = states.iter().map(|state| state.watts).collect();
+        store.insert(&ids, timestamp, &readings)?;
+
+        for ((id, state), stored) in ids.iter().zip(states).zip(&mut infos) {
+            if let Some(info) = &state.info {
+                let current = (info.model.clone(), info.nickname.clone());
+                if stored.as_ref() != Some(&current) {
+                    store.set_info(*id, info).with_context(|| {
+                        format!("Storing the model and nickname of {}", state.name)
+                    })?;
+                    *stored = Some(current);
+                }
+            }
+        }
+        // Once a minute is complete.
+        let current = timestamp.timestamp_millis().div_euclid(MINUTE);
+        if minute.is_some_and(|minute| minute != current) {
+            store.roll_up(timestamp, &retention)?;
+        }
+        minute = Some(current);
+        Ok(())
+    };
+
+    let result = select! {
+        result = poll(&mut connections, &states, every, store_readings) => {
+            result.with_context(|| format!("Writing {}", path.display()))
+        }
+        _ = ctrl_c() => Ok(()),
+    };
+
+    print_outages(&connections);
+    result
+}
+
+pub fn print(rows: &[Row], resolution: Resolution, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Human => {
+            if rows.is_empty() {
+                eprintln!("No readings in that time range");
+            }
+            for row in rows {
+                print_human(row, resolution);
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(rows)?),
+        OutputFormat::Toml => print!("{}", toml_list("rows", rows)?),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn print_human(row: &Row, resolution: Resolution) {
+    let timestamp = row.timestamp.with_timezone(&Local);
+    let (Some(mean_w), Some(min_w), Some(max_w)) = (row.mean_w, row.min_w, row.max_w) else {
+        println!(
+            "{}  {}: unreachable",
+            timestamp.format("%Y-%m-%d %H:%M:%S"),
+            row.device
+        );
+        return;
+    };
+    match resolution {
+        Resolution::Raw => println!(
+            "{}  {}: {mean_w} W",
+            timestamp.format("%Y-%m-%d %H:%M:%S"),
+            row.device
+        ),
+        Resolution::Minute | Resolution::Hour => println!(
+            "{}  {}: avg {mean_w:.1} W, min {min_w} W, max {max_w} W{}",
+            timestamp.format("%Y-%m-%d %H:%M"),
+            row.device,
+            match row.missing {
+                0 => String::new(),
+                missing => format!(", {missing} of {} missing", row.readings + missing),
+            }
+        ),
+    }
+}
+
+fn millis(duration: Duration) -> i64 {
+    duration.as_millis().try_into().unwrap_or(i64::MAX)
+}
@@ -0,0 +1,12 @@
+use anyhow::{Context, Result, bail};
+use reqwest::Url;
+
+/// Parses the URL of a server we talk to over HTTP, such as InfluxDB or a running `daemon`.
+/// Anything but `http://` and `https://` is refused, the HTTP client couldn't use it.
+pub fn parse_url(url: &str) -> Result<Url> {
+    let url = Url::parse(url).with_context(|| format!("Invalid URL {url:?}"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("Expected an http:// or https:// URL, not {url}");
+    }
+    Ok(url)
+}
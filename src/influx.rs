@@ -0,0 +1,322 @@
+use crate::{http::parse_url, polling::DeviceState, serve::Output};
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::{
+    Client, StatusCode, Url,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+};
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    task::JoinHandle,
+    time::{MissedTickBehavior, interval},
+};
+
+/// How many lines to hold on to while the server can't be reached. The oldest are dropped.
+const MAX_PENDING_LINES: usize = 100_000;
+/// How long to wait for the server to take a batch.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where and how to write readings as InfluxDB line protocol.
+#[derive(clap::Args, Clone, Debug)]
+pub struct InfluxArgs {
+    /// POST the readings as InfluxDB line protocol to this URL, e.g.
+    /// `http://localhost:8086/api/v2/write?org=home&bucket=power`, or to anything else taking
+    /// line protocol over HTTP.
+    #[arg(
+        long,
+        value_name = "URL",
+        value_parser = parse_url,
+        group = "outputs",
+        conflicts_with = "influx_file"
+    )]
+    influx_url: Option<Url>,
+    /// Sent as `Authorization: Token <TOKEN>`. Pass it through INFLUX_TOKEN to keep it out of the
+    /// process list.
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        env = "INFLUX_TOKEN",
+        hide_env_values = true
+    )]
+    influx_token: Option<String>,
+    /// Append the readings as InfluxDB line protocol to this file instead, `-` for stdout.
+    #[arg(long, value_name = "FILE", group = "outputs")]
+    influx_file: Option<PathBuf>,
+    /// The measurement to write the readings to.
+    #[arg(long, value_name = "NAME", default_value = "power")]
+    influx_measurement: String,
+    /// Time between two batches of readings written.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "10s")]
+    influx_flush_interval: Duration,
+}
+
+/// Where the batches go.
+enum Destination {
+    Http {
+        client: Client,
+        url: Url,
+        token: Option<String>,
+    },
+    File {
+        /// For messages.
+        name: String,
+        writer: Box<dyn Write + Send>,
+    },
+}
+
+/// Turns the readings of the devices into line protocol, written in batches in the background.
+struct Writer {
+    lines: UnboundedSender<String>,
+    task: JoinHandle<()>,
+    measurement: String,
+    host: String,
+}
+
+/// Lines waiting to be written, kept around while the destination fails.
+struct Batcher {
+    destination: Destination,
+    pending: VecDeque<String>,
+    /// Whether writing fails, to warn only once about it.
+    failing: bool,
+    /// Whether lines are being dropped, to warn only once about it.
+    dropping: bool,
+}
+
+impl InfluxArgs {
+    /// Starts writing batches in the background if asked to.
+    pub fn output(&self, _devices: &Mutex<Vec<DeviceState>>) -> Result<Option<Box<dyn Output>>> {
+        let destination = if let Some(url) = &self.influx_url {
+            Destination::Http {
+                client: Client::builder()
+                    .timeout(REQUEST_TIMEOUT)
+                    .user_agent(concat!(
+                        env!("CARGO_PKG_NAME"),
+                        "/",
+                        env!("CARGO_PKG_VERSION")
+                    ))
+                    .build()?,
+                url: url.clone(),
+                token: self.influx_token.clone(),
+            }
+        } else if let Some(path) = &self.influx_file {
+            if path.as_os_str() == "-" {
+                Destination::File {
+                    name: "stdout".to_string(),
+                    writer: Box::new(io::stdout()),
+                }
+            } else {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Opening {}", path.display()))?;
+                Destination::File {
+                    name: path.display().to_string(),
+                    writer: Box::new(file),
+                }
+            }
+        } else {
+            return Ok(None);
+        };
+        let host = hostname::get()
+            .context("Getting the hostname to tag the readings with")?
+            .to_string_lossy()
+            .into_owned();
+
+        let (lines, receiver) = unbounded_channel();
+        let batcher = Batcher {
+            destination,
+            pending: VecDeque::new(),
+            failing: false,
+            dropping: false,
+        };
+        Ok(Some(Box::new(Writer {
+            lines,
+            task: tokio::spawn(batcher.run(receiver, self.influx_flush_interval)),
+            measurement: self.influx_measurement.clone(),
+            host,
+        })))
+    }
+}
+
+#[async_trait]
+impl Output for Writer {
+    /// Queues a line per device for the next batch.
+    fn publish(&mut self, devices: &[DeviceState], timestamp: DateTime<Utc>) -> Result<()> {
+        for device in devices {
+            let line = line(&self.measurement, &self.host, device, timestamp);
+            // The batcher only stops once we close.
+            let _ = self.lines.send(line);
+        }
+        Ok(())
+    }
+
+    /// Writes what is left, giving up after a last attempt.
+    async fn close(self: Box<Self>) {
+        drop(self.lines);
+        let _ = self.task.await;
+    }
+}
+
+impl Batcher {
+    async fn run(mut self, mut lines: UnboundedReceiver<String>, every: Duration) {
+        let mut ticks = interval(every);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick is immediate, there is nothing to write yet.
+        ticks.tick().await;
+        loop {
+            select! {
+                line = lines.recv() => match line {
+                    Some(line) => self.push(line),
+                    None => break,
+                },
+                _ = ticks.tick() => self.flush().await,
+            }
+        }
+        self.flush().await;
+    }
+
+    fn push(&mut self, line: String) {
+        if self.pending.len() >= MAX_PENDING_LINES {
+            if !self.dropping {
+                eprintln!(
+                    "warning: dropping the oldest InfluxDB lines, {MAX_PENDING_LINES} are waiting \
+                        to be written"
+                );
+            }
+            self.dropping = true;
+            self.pending.pop_front();
+        }
+        self.pending.push_back(line);
+    }
+
+    async fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let mut body = self.pending.make_contiguous().join("\n");
+        body.push('\n');
+        match self.destination.write(body).await {
+            Ok(()) => {
+                if self.failing {
+                    eprintln!("Writing to {} again", self.destination);
+                }
+                self.pending.clear();
+                self.failing = false;
+                self.dropping = false;
+            }
+            Err(error) => {
+                if !self.failing {
+                    eprintln!(
+                        "warning: writing to {}: {error:#}, retrying",
+                        self.destination
+                    );
+                }
+                self.failing = true;
+            }
+        }
+    }
+}
+
+impl Destination {
+    async fn write(&mut self, body: String) -> Result<()> {
+        match self {
+            Self::Http { client, url, token } => {
+                let mut request = client
+                    .post(url.clone())
+                    .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                    .body(body);
+                if let Some(token) = token {
+                    request = request.header(AUTHORIZATION, format!("Token {token}"));
+                }
+                let response = request.send().await?;
+                let status = response.status();
+                if status.is_success() {
+                    return Ok(());
+                }
+                let message = response.text().await.unwrap_or_default();
+                // Sending the same lines again won't help, unless we are merely told to slow down.
+                if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+                    eprintln!(
+                        "warning: {} rejected the readings, dropping them: {status} {}",
+                        url.host_str().unwrap_or_default(),
+                        message.trim()
+                    );
+                    return Ok(());
+                }
+                bail!("{status} {}", message.trim())
+            }
+            Self::File { writer, .. } => {
+                writer.write_all(body.as_bytes())?;
+                Ok(writer.flush()?)
+            }
+        }
+    }
+}
+
+/// Without the token or query, for messages.
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http { url, .. } => {
+                write!(f, "{}{}", url.origin().ascii_serialization(), url.path())
+            }
+            Self::File { name, .. } => write!(f, "{name}"),
+        }
+    }
+}
+
+/// A device's latest reading in line protocol, e.g.
+/// `power,device=desk,host=studio,model=P115 watts=42i,energy_wh=1.5,up=true 1750000000000000000`.
+/// Unreachable devices have no `watts`, and the model is missing until the device first answered.
+fn line(measurement: &str, host: &str, device: &DeviceState, timestamp: DateTime<Utc>) -> String {
+    let mut line = escape(measurement, &[',', ' ']);
+    // Sorted by key, as InfluxDB prefers.
+    let _ = write!(line, ",device={}", escape(&device.name, &[',', '=', ' ']));
+    let _ = write!(line, ",host={}", escape(host, &[',', '=', ' ']));
+    if let Some(info) = &device.info
+        && !info.model.is_empty()
+    {
+        let _ = write!(line, ",model={}", escape(&info.model, &[',', '=', ' ']));
+    }
+
+    let mut fields = Vec::new();
+    if let Some(watts) = device.watts {
+        fields.push(format!("watts={watts}i"));
+    }
+    fields.push(format!("energy_wh={}", device.energy_meter.watt_hours()));
+    if let Some(usage) = &device.energy_usage {
+        fields.push(format!("today_energy_wh={}i", usage.today_energy));
+        fields.push(format!("month_energy_wh={}i", usage.month_energy));
+    }
+    fields.push(format!("up={}", device.up));
+    let _ = write!(
+        line,
+        " {} {}",
+        fields.join(","),
+        timestamp.timestamp_nanos_opt().unwrap_or_default()
+    );
+    line
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,228 @@
+use crate::{
+    config::{self, DeviceAddress, Model},
+    credentials::Credentials,
+    discover::{self, DiscoveredDevice},
+};
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use tapo::ApiClient;
+
+/// How long to wait for devices to answer discovery when they aren't in the cache.
+const DISCOVERY_WAIT: Duration = Duration::from_secs(3);
+
+/// A device identified by something more stable than its IP address, which changes whenever its
+/// DHCP lease does.
+#[derive(Clone, Debug)]
+pub enum DeviceQuery {
+    /// The name given to the device in the Tapo app. Case insensitive.
+    Nickname(String),
+    Mac(MacAddress),
+}
+
+/// A MAC address, normalized to the `AA-BB-CC-DD-EE-FF` form devices report.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MacAddress(String);
+
+/// Where a device was found.
+pub struct FoundDevice {
+    pub address: DeviceAddress,
+    pub model: Model,
+}
+
+/// The devices found by earlier lookups, so that we don't have to wait for discovery every time.
+#[derive(Default, Serialize, Deserialize)]
+struct Cache {
+    #[serde(default)]
+    devices: Vec<CachedDevice>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedDevice {
+    mac: String,
+    nickname: Option<String>,
+    ip: IpAddr,
+    port: u16,
+    /// As reported by the device, e.g. `P115(EU)`.
+    model: String,
+}
+
+impl DeviceQuery {
+    fn matches(&self, mac: &str, nickname: Option<&str>) -> bool {
+        match self {
+            DeviceQuery::Nickname(wanted) => {
+                nickname.is_some_and(|nickname| nickname.eq_ignore_ascii_case(wanted))
+            }
+            DeviceQuery::Mac(wanted) => mac.parse().is_ok_and(|mac: MacAddress| mac == *wanted),
+        }
+    }
+}
+
+impl Display for DeviceQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceQuery::Nickname(nickname) => write!(f, "{nickname}"),
+            DeviceQuery::Mac(mac) => write!(f, "{mac}"),
+        }
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(mac: &str) -> Result<Self> {
+        let digits: String = mac
+            .chars()
+            .filter(|c| !matches!(c, ':' | '-'))
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Invalid MAC address {mac:?}, expected e.g. aa:bb:cc:dd:ee:ff");
+        }
+        let octets: Vec<&str> = (0..12).step_by(2).map(|i| &digits[i..i + 2]).collect();
+        Ok(Self(octets.join("-")))
+    }
+}
+
+impl Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl CachedDevice {
+    fn new(device: &DiscoveredDevice, nickname: Option<String>) -> Self {
+        Self {
+            mac: device.mac.clone(),
+            nickname,
+            ip: device.ip,
+            port: device.port,
+            model: device.model.clone(),
+        }
+    }
+
+    fn found(&self) -> Result<FoundDevice> {
+        let model = Model::detect(&self.model).with_context(|| {
+            format!(
+                "Can't read from {} at {}",
+                self.nickname.as_deref().unwrap_or(&self.mac),
+                self.ip,
+            )
+        })?;
+        Ok(FoundDevice {
+            address: DeviceAddress {
+                ip: self.ip,
+                port: Some(self.port),
+            },
+            model,
+        })
+    }
+
+    /// Whether the device still answers at the cached address, and is still the one we want.
+    async fn is_current(
+        &self,
+        query: &DeviceQuery,
+        credentials: &Credentials,
+        timeout: Duration,
+    ) -> bool {
+        let address = SocketAddr::new(self.ip, self.port).to_string();
+        let info = async {
+            ApiClient::new(&credentials.username, &credentials.password)
+                .with_timeout(timeout)
+                .generic_device(address)
+                .await?
+                .get_device_info()
+                .await
+        };
+        info.await
+            .is_ok_and(|info| query.matches(&info.mac, Some(&info.nickname)))
+    }
+}
+
+/// Finds the device by looking at the cached address first, then by asking all devices on the
+/// local network. Updates the cache with what discovery finds.
+pub async fn find(
+    query: &DeviceQuery,
+    credentials: &Credentials,
+    discovery_target: SocketAddr,
+    timeout: Duration,
+) -> Result<FoundDevice> {
+    let mut cache = Cache::load();
+    let cached = cache
+        .devices
+        .iter()
+        .find(|device| query.matches(&device.mac, device.nickname.as_deref()));
+    if let Some(device) = cached
+        && device.is_current(query, credentials, timeout).await
+    {
+        return device.found();
+    }
+
+    let devices = discover::discover(discovery_target, DISCOVERY_WAIT).await?;
+    // Only needed to match by nickname, but worth caching either way.
+    let nicknames = discover::nicknames(&devices, credentials, timeout).await;
+    let found: Vec<CachedDevice> = devices
+        .iter()
+        .zip(nicknames)
+        .map(|(device, nickname)| CachedDevice::new(device, nickname))
+        .collect();
+
+    cache.update(&found);
+    if let Err(error) = cache.save() {
+        eprintln!("warning: not caching device addresses: {error:#}");
+    }
+
+    found
+        .iter()
+        .find(|device| query.matches(&device.mac, device.nickname.as_deref()))
+        .with_context(|| {
+            let device = match query {
+                DeviceQuery::Nickname(nickname) => format!("named {nickname:?}"),
+                DeviceQuery::Mac(mac) => format!("with MAC address {mac}"),
+            };
+            format!("No device {device} on the local network, `discover` lists the ones it finds")
+        })?
+        .found()
+}
+
+impl Cache {
+    /// An empty cache if there is none yet or it can't be read. It only saves time.
+    fn load() -> Self {
+        cache_path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = cache_path().context("Neither XDG_CACHE_HOME nor HOME is set")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Creating cache directory {}", dir.display()))?;
+        }
+        std::fs::write(&path, toml::to_string(self)?)
+            .with_context(|| format!("Writing device cache {}", path.display()))
+    }
+
+    /// Replaces what we knew about the devices in `found`, keeping the others. They may just have
+    /// missed the discovery request.
+    fn update(&mut self, found: &[CachedDevice]) {
+        self.devices.retain(|cached| {
+            !found
+                .iter()
+                .any(|device| device.mac == cached.mac || device.ip == cached.ip)
+        });
+        self.devices.extend_from_slice(found);
+    }
+}
+
+/// `$XDG_CACHE_HOME/tapo-power-monitor/devices.toml`, falling back to `~/.cache`.
+fn cache_path() -> Option<PathBuf> {
+    Some(config::app_dir("XDG_CACHE_HOME", ".cache")?.join("devices.toml"))
+}
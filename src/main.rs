@@ -1,132 +1,768 @@
-use anyhow::{Context, Result};
+#[cfg(feature = "history")]
+use crate::history::{Resolution, Retention, Store};
+#[cfg(feature = "serve")]
+use crate::serve::ServeArgs;
+use crate::{
+    analyze::Options,
+    config::{Config, DeviceAddress, DeviceConfig, Model},
+    credentials::{CredentialArgs, Credentials},
+    diagnosis::diagnose,
+    lookup::{DeviceQuery, MacAddress},
+    measure::{Measurement, OutputFormat, StopCondition, Tolerance, get_samples},
+    monitor::MonitorOutput,
+    power_source::Device,
+    recording::{Session, Speed},
+    simulator::{Profile, SimulatedPlug},
+    tariff::Tariff,
+};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
 use console::Term;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::{env, net::IpAddr, time::Duration};
-use tapo::{ApiClient, PlugEnergyMonitoringHandler};
-use textplots::{Chart, LabelBuilder, LabelFormat, Plot, Shape};
-use tokio::time::sleep;
+use futures::future::try_join_all;
+#[cfg(feature = "daemon")]
+use reqwest::Url;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    process::ExitCode,
+    str::FromStr,
+    time::Duration,
+};
+use tapo::ApiClient;
+
+mod analyze;
+mod config;
+mod connection;
+mod cost;
+mod credentials;
+#[cfg(feature = "daemon")]
+mod daemon;
+mod diagnosis;
+mod discover;
+mod doctor;
+mod energy;
+#[cfg(feature = "history")]
+mod history;
+#[cfg(any(feature = "influx", feature = "daemon"))]
+mod http;
+#[cfg(feature = "influx")]
+mod influx;
+mod lookup;
+mod measure;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod monitor;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "polling")]
+mod polling;
+mod power_source;
+mod recording;
+mod run;
+#[cfg(feature = "serve")]
+mod serve;
+mod simulator;
+mod tariff;
 
 /// Empirically estimated maximum update-rate of the Tapo 'current power' reading.
 /// Querying the device more frequently than this is pointless.
 const TAPO_TEMPORAL_RESOLUTION: Duration = Duration::from_secs(1);
 
-// How many samples we take for a single measurement.
+// How many samples we take for a single measurement by default.
 const MEASUREMENT_SAMPLE_COUNT: usize = 10;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let args = Args::parse();
-    let username = env::var("TAPO_USERNAME")
-        .context("Getting Tapo username from TAPO_USERNAME environment variable")?;
-    let password = env::var("TAPO_PASSWORD")
-        .context("Getting Tapo password from TAPO_PASSWORD environment variable")?;
-
-    let device = ApiClient::new(username, password)
-        .p115(&args.ip.to_string())
-        .await
-        .context("Connecting to the device")?;
+    let config = Config::load(args.config.as_deref())?;
+    let tariff = args.tariff(&config)?;
+    if args.devices.len() > 1 && !args.command.supports_several_devices() {
+        bail!("This command reads from a single device, pass only one --device");
+    }
+    // The commands not simply reading from the devices.
+    if let TapoCommand::Discover { wait } = args.command {
+        let devices = discover::discover(args.discovery_target, wait).await?;
+        if devices.is_empty() {
+            eprintln!(
+                "No devices answered within {}",
+                humantime::format_duration(wait)
+            );
+            return Ok(ExitCode::FAILURE);
+        }
+        let credentials = match args.credentials.resolve(&config).await {
+            Ok(credentials) => Some(credentials),
+            Err(error) => {
+                eprintln!("Not looking up nicknames: {error}");
+                None
+            }
+        };
+        discover::print(&devices, credentials.as_ref(), args.timeout).await;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let TapoCommand::Doctor = args.command {
+        return Ok(if doctor::run(&args, &config).await {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+    if let TapoCommand::Replay {
+        recording,
+        speed,
+        total,
+    } = &args.command
+    {
+        let session = Session::load(recording)?;
+        monitor::replay(session, *speed, *total, tariff).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let TapoCommand::Analyze {
+        trace,
+        from,
+        to,
+        on_above,
+        threshold,
+        format,
+    } = &args.command
+    {
+        let options = Options {
+            from: *from,
+            to: *to,
+            on_above: *on_above,
+            threshold: *threshold,
+        };
+        let analyses = analyze::analyze(trace, &options, tariff)?;
+        analyze::print(&analyses, format.or(config.format).unwrap_or_default())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    #[cfg(feature = "history")]
+    if let TapoCommand::Query {
+        db,
+        from,
+        to,
+        resolution,
+        format,
+    } = &args.command
+    {
+        // Only the names of the devices matter.
+        let names = args.device_names();
+        let to = to.unwrap_or_else(Utc::now);
+        let from = from.unwrap_or(to - chrono::TimeDelta::days(1));
+        let store = Store::open(db)?;
+        let resolution = match resolution {
+            Some(resolution) => *resolution,
+            None => store.pick_resolution(from, to)?,
+        };
+        let rows = store.query(&names, from, to, resolution)?;
+        history::print(
+            &rows,
+            resolution,
+            format.or(config.format).unwrap_or_default(),
+        )?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let devices = connect(&args, &config).await?;
+    // Only possible through the daemon, which reads from all its devices unless named.
+    if devices.len() > 1 && !args.command.supports_several_devices() {
+        bail!(
+            "Name one of the devices: {}",
+            devices
+                .iter()
+                .map(|device| device.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    // All but `monitor` work with a single device.
+    let device = &devices[0];
 
     match args.command {
-        TapoCommand::Measure => {
-            let samples = get_samples(device).await?;
-            print_stats(&samples);
+        TapoCommand::Measure {
+            samples,
+            duration,
+            until_stable,
+            max_duration,
+            interval,
+            format,
+        } => {
+            let interval = sampling_interval(interval.or(config.interval));
+            let stop = StopCondition::new(samples, duration, until_stable, max_duration, interval);
+            let usage_before = device.source.energy_usage().await?;
+            let samples = get_samples(device.source.as_ref(), stop, interval).await?;
+            let usage_after = device.source.energy_usage().await?;
+
+            let device_counter =
+                energy::counter_increase(usage_before.today_energy, usage_after.today_energy);
+            Measurement::new(
+                device.name.clone(),
+                interval,
+                samples,
+                device_counter,
+                tariff,
+            )
+            .print(format.or(config.format).unwrap_or_default())?;
+        }
+        TapoCommand::Monitor {
+            output,
+            total,
+            record,
+        } => {
+            let output = output.unwrap_or_else(|| {
+                if Term::stdout().is_term() {
+                    MonitorOutput::Plot
+                } else {
+                    MonitorOutput::Ndjson
+                }
+            });
+            monitor::monitor(devices, total, output, tariff, record).await?;
+        }
+        #[cfg(feature = "serve")]
+        TapoCommand::Serve { interval, outputs } => {
+            let interval = sampling_interval(interval.or(config.interval));
+            serve::serve(devices, &outputs, interval).await?;
+        }
+        #[cfg(feature = "daemon")]
+        TapoCommand::Daemon {
+            listen,
+            history,
+            interval,
+        } => {
+            let interval = sampling_interval(interval.or(config.interval));
+            daemon::daemon(devices, listen, history, interval).await?;
+        }
+        #[cfg(feature = "history")]
+        TapoCommand::Log {
+            db,
+            keep_raw,
+            keep_minutes,
+            interval,
+        } => {
+            let interval = sampling_interval(interval.or(config.interval));
+            let retention = Retention {
+                raw: keep_raw,
+                minutes: keep_minutes,
+            };
+            history::log(devices, &db, retention, interval).await?;
         }
-        TapoCommand::Monitor => monitor(device).await?,
+        TapoCommand::Run {
+            baseline_samples,
+            interval,
+            command,
+        } => {
+            let interval = sampling_interval(interval.or(config.interval));
+            let status = run::run(
+                device.source.as_ref(),
+                &command,
+                baseline_samples,
+                interval,
+                tariff,
+            )
+            .await?;
+            // Pass on the command's exit code so that `run` can be used in scripts transparently.
+            let code = status.code().unwrap_or(1);
+            return Ok(ExitCode::from(u8::try_from(code).unwrap_or(1)));
+        }
+        TapoCommand::Cost => {
+            let Some(tariff) = tariff else {
+                bail!("Estimating cost needs a tariff, pass --price-per-kwh or --tariff");
+            };
+            cost::report(device.source.as_ref(), &tariff).await?;
+        }
+        TapoCommand::Discover { .. }
+        | TapoCommand::Doctor
+        | TapoCommand::Replay { .. }
+        | TapoCommand::Analyze { .. } => unreachable!("handled above"),
+        #[cfg(feature = "history")]
+        TapoCommand::Query { .. } => unreachable!("handled above"),
     };
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-async fn get_samples(device: PlugEnergyMonitoringHandler) -> Result<Vec<u64>> {
-    let progress_bar_style = ProgressStyle::with_template(
-        "obtaining samples... [{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7}",
+async fn connect(args: &Args, config: &Config) -> Result<Vec<Device>> {
+    if let Some(profile) = &args.simulate {
+        return Ok(vec![Device {
+            name: "simulated".to_string(),
+            source: Box::new(SimulatedPlug::new(profile.clone())),
+        }]);
+    }
+    #[cfg(feature = "daemon")]
+    if args.daemon {
+        return daemon::connect(&args.daemon_url, &args.device_names(), args.timeout).await;
+    }
+
+    let credentials = args.credentials.resolve(config).await?;
+    let specs = args.find_devices(config, &credentials).await?;
+    try_join_all(
+        specs
+            .into_iter()
+            .map(|spec| connect_device(spec, &credentials, args.timeout)),
     )
-    .expect("valid style");
-    let progress_bar =
-        ProgressBar::new(MEASUREMENT_SAMPLE_COUNT as u64).with_style(progress_bar_style);
+    .await
+}
 
-    let mut samples = Vec::new();
-    for _ in 0..MEASUREMENT_SAMPLE_COUNT {
-        samples.push(device.get_current_power().await?.current_power);
-        progress_bar.inc(1);
-        sleep(TAPO_TEMPORAL_RESOLUTION).await;
-    }
+/// Logs in to a device and makes sure it measures power, explaining common failures.
+async fn connect_device(
+    spec: DeviceSpec,
+    credentials: &Credentials,
+    timeout: Duration,
+) -> Result<Device> {
+    let address = spec.address.to_api_address();
+    let connected = async {
+        let client =
+            ApiClient::new(&credentials.username, &credentials.password).with_timeout(timeout);
+        // All the models we support are read the same way, the model only serves as a check.
+        let device = client.p115(address.clone()).await?;
+        let info = device.get_device_info_json().await?;
+        let device_model = info["model"].as_str().unwrap_or_default();
+        let model = Model::detect(device_model)?;
+        if spec.model != Model::Auto && spec.model != model {
+            eprintln!(
+                "warning: {} is configured as a {:?} but says it is a {device_model}",
+                spec.name, spec.model
+            );
+        }
+        anyhow::Ok(device)
+    };
+    let device = connected.await.map_err(|error| {
+        diagnose(
+            error.context(format!("Connecting to the device at {address}")),
+            &address,
+        )
+    })?;
 
-    progress_bar.finish_and_clear();
+    Ok(Device {
+        name: spec.name,
+        source: Box::new(device),
+    })
+}
 
-    Ok(samples)
+/// Clamps the requested sampling interval to what the device can actually deliver. Samples as fast
+/// as possible by default.
+fn sampling_interval(requested: Option<Duration>) -> Duration {
+    let requested = requested.unwrap_or(TAPO_TEMPORAL_RESOLUTION);
+    if requested < TAPO_TEMPORAL_RESOLUTION {
+        eprintln!(
+            "warning: the device only updates its reading every {}, sampling at that rate instead",
+            humantime::format_duration(TAPO_TEMPORAL_RESOLUTION)
+        );
+        return TAPO_TEMPORAL_RESOLUTION;
+    }
+
+    requested
 }
 
-fn print_stats(samples: &Vec<u64>) {
-    let max = samples.iter().max().expect("we obtained samples");
-    let min = samples.iter().min().expect("we obtained samples");
-
-    let len = samples.len() as f32;
-    let samples_f32 = samples.iter().map(|sample| *sample as f32);
-    let mean = samples_f32.clone().sum::<f32>() / len;
-    let variance: f32 = samples_f32
-        .map(|sample| (sample - mean).powi(2))
-        .sum::<f32>()
-        / len;
-    let standard_deviation = variance.sqrt();
-
-    println!("avg: {mean:.1} W +-{standard_deviation:.1} W");
-    println!("min: {min} W");
-    println!("max: {max} W");
-    println!("samples: {:?}", samples);
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// IP address of the device, or its name in the config file.
+    device: Option<String>,
+    /// Read from several devices, e.g. `--device desk=192.168.1.20 --device printer=192.168.1.21`.
+    /// The names are used in the output. Devices from the config file can be given by name only.
+    /// Commands reading from a single device, such as `measure`, only take one.
+    #[arg(
+        long = "device",
+        value_name = "NAME[=IP[:PORT]]",
+        conflicts_with_all = ["device", "simulate", "port"]
+    )]
+    devices: Vec<NamedDevice>,
+    /// Read from a simulated plug instead of a real device. PROFILE is one of constant:<W>,
+    /// square:<LOW W>:<HIGH W>:<PERIOD>, noisy-idle:<W>[:<NOISE W>], ramp:<FROM W>:<TO W>:<DURATION>
+    /// or replay:<CSV FILE>.
+    #[arg(long, value_name = "PROFILE", conflicts_with = "device")]
+    simulate: Option<Profile>,
+    /// Find the device by the name it was given in the Tapo app, instead of by its IP address
+    /// which may change. Found devices are cached, so only the first lookup waits for discovery.
+    #[arg(
+        long,
+        value_name = "NICKNAME",
+        conflicts_with_all = ["device", "devices", "simulate", "port"]
+    )]
+    device_name: Option<String>,
+    /// Find the device by its MAC address, like --device-name.
+    #[arg(long, conflicts_with_all = ["device", "devices", "simulate", "port", "device_name"])]
+    mac: Option<MacAddress>,
+    /// Where to send discovery requests for `discover`, --device-name and --mac. Broadcasts to
+    /// the local network by default.
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        default_value_t = SocketAddr::from((Ipv4Addr::BROADCAST, discover::DISCOVERY_PORT))
+    )]
+    discovery_target: SocketAddr,
+    /// The model the devices are expected to be, warning if one says otherwise. They are detected
+    /// and read the same way regardless. Overrides the model in the config file.
+    #[arg(long, value_enum, conflicts_with = "simulate")]
+    model: Option<Model>,
+    /// Give up on requests to a device after this long.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    timeout: Duration,
+    /// Port of the device's local API, if not the default 80. Mostly useful with `tapo-emulator`.
+    #[arg(long, requires = "device")]
+    port: Option<u16>,
+    /// Estimate what the consumed energy costs at this flat price.
+    #[arg(long, value_name = "PRICE", conflicts_with = "tariff")]
+    price_per_kwh: Option<f64>,
+    /// Currency of --price-per-kwh, only used for display.
+    #[arg(long, requires = "price_per_kwh", default_value = "")]
+    currency: String,
+    /// Estimate what the consumed energy costs according to a TOML file with flat or time-of-use
+    /// prices, see the README. Overrides the tariff in the config file.
+    #[arg(long, value_name = "FILE")]
+    tariff: Option<PathBuf>,
+    /// Read from the devices through a running `daemon` instead of connecting to them, sharing
+    /// its sessions. Devices are named as the daemon names them, all of them are read from unless
+    /// some are named.
+    #[cfg(feature = "daemon")]
+    #[arg(long, conflicts_with_all = ["simulate", "device_name", "mac", "port"])]
+    daemon: bool,
+    /// Where the daemon serves its API.
+    #[cfg(feature = "daemon")]
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "TAPO_DAEMON_URL",
+        default_value = "http://127.0.0.1:9585",
+        value_parser = http::parse_url
+    )]
+    daemon_url: Url,
+    #[command(flatten)]
+    credentials: CredentialArgs,
+    /// Read settings from this file instead of `tapo-power-monitor/config.toml` in the user's
+    /// config directory.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: TapoCommand,
 }
 
-// Inspired by https://github.com/loony-bean/textplots-rs/blob/master/examples/liveplot.rs.
-async fn monitor(device: PlugEnergyMonitoringHandler) -> Result<()> {
-    const PLOT_WIDTH: usize = 100;
+impl Args {
+    fn tariff(&self, config: &Config) -> Result<Option<Tariff>> {
+        match (&self.tariff, self.price_per_kwh) {
+            (Some(path), _) => Ok(Some(Tariff::load(path)?)),
+            (None, Some(price)) => Ok(Some(Tariff::flat(price, self.currency.clone()))),
+            (None, None) => Ok(config.tariff.clone()),
+        }
+    }
+
+    /// The devices to connect to, looking them up on the local network if given by nickname or
+    /// MAC address.
+    async fn find_devices(
+        &self,
+        config: &Config,
+        credentials: &Credentials,
+    ) -> Result<Vec<DeviceSpec>> {
+        let mut specs = match self.device_query() {
+            Some(query) => {
+                let found =
+                    lookup::find(&query, credentials, self.discovery_target, self.timeout).await?;
+                vec![DeviceSpec {
+                    name: query.to_string(),
+                    address: found.address,
+                    model: found.model,
+                }]
+            }
+            None => self.device_specs(config)?,
+        };
+        if let Some(model) = self.model {
+            for spec in &mut specs {
+                spec.model = model;
+            }
+        }
+        Ok(specs)
+    }
+
+    /// The names of the devices given, without looking them up.
+    #[cfg(any(feature = "history", feature = "daemon"))]
+    fn device_names(&self) -> Vec<String> {
+        self.device
+            .iter()
+            .cloned()
+            .chain(self.devices.iter().map(|device| device.name.clone()))
+            .collect()
+    }
 
-    let term = Term::stdout();
-    term.clear_screen().unwrap();
+    fn device_query(&self) -> Option<DeviceQuery> {
+        match (&self.device_name, &self.mac) {
+            (Some(nickname), _) => Some(DeviceQuery::Nickname(nickname.clone())),
+            (None, Some(mac)) => Some(DeviceQuery::Mac(mac.clone())),
+            (None, None) => None,
+        }
+    }
 
-    let mut samples: Vec<(f32, f32)> = Vec::new();
-    loop {
-        // Shift the collected samples.
-        for sample in samples.iter_mut() {
-            sample.0 -= 1.0;
+    /// The devices to connect to, with names and addresses resolved through the config file.
+    fn device_specs(&self, config: &Config) -> Result<Vec<DeviceSpec>> {
+        if let Some(device) = &self.device {
+            let mut spec = match device.parse::<DeviceAddress>() {
+                Ok(address) => DeviceSpec {
+                    name: device.clone(),
+                    address,
+                    model: Model::default(),
+                },
+                Err(_) => DeviceSpec::from_config(device, config)?,
+            };
+            if let Some(port) = self.port {
+                spec.address.port = Some(port);
+            }
+            return Ok(vec![spec]);
         }
-        if samples.len() == PLOT_WIDTH {
-            samples.remove(0);
+        if self.devices.is_empty() {
+            // `daemon` reads from all devices in the config file by default.
+            #[cfg(feature = "daemon")]
+            if let TapoCommand::Daemon { .. } = self.command
+                && !config.devices.is_empty()
+            {
+                return config
+                    .devices
+                    .keys()
+                    .map(|name| DeviceSpec::from_config(name, config))
+                    .collect();
+            }
+            bail!(
+                "No device given, pass its IP address, its name from the config file or \
+                    --device-name. `discover` lists the devices on the local network."
+            );
         }
 
-        // Get the next sample.
-        let sample = device.get_current_power().await?.current_power;
-        samples.push((0., sample as f32));
+        self.devices
+            .iter()
+            .map(|device| match device.address {
+                Some(address) => Ok(DeviceSpec {
+                    name: device.name.clone(),
+                    address,
+                    model: config
+                        .devices
+                        .get(&device.name)
+                        .map(DeviceConfig::model)
+                        .unwrap_or_default(),
+                }),
+                None => DeviceSpec::from_config(&device.name, config),
+            })
+            .collect()
+    }
+}
 
-        // Update the plot.
-        term.move_cursor_to(0, 0).unwrap();
-        Chart::new(200, 50, -(PLOT_WIDTH as f32), 0.0)
-            .x_label_format(LabelFormat::Custom(Box::new(|ts| match ts {
-                0.0 => "now".to_string(),
-                ts => format!("{ts:.0} seconds"),
-            })))
-            .y_label_format(LabelFormat::Custom(Box::new(|watts| format!("{watts} W"))))
-            .lineplot(&Shape::Steps(&samples))
-            .nice();
+/// A device given with `--device NAME[=IP[:PORT]]`.
+#[derive(Clone, Debug)]
+struct NamedDevice {
+    name: String,
+    /// Looked up in the config file if not given.
+    address: Option<DeviceAddress>,
+}
 
-        println!("current power: {sample}W");
+impl FromStr for NamedDevice {
+    type Err = anyhow::Error;
 
-        sleep(TAPO_TEMPORAL_RESOLUTION).await;
+    fn from_str(device: &str) -> Result<Self> {
+        let (name, address) = match device.split_once('=') {
+            Some((name, address)) => (name, Some(address.parse()?)),
+            None => (device, None),
+        };
+        if name.is_empty() {
+            bail!("The device name must not be empty");
+        }
+        Ok(Self {
+            name: name.to_string(),
+            address,
+        })
     }
 }
 
-#[derive(Parser, Debug)]
-#[command(author, version, about)]
-struct Args {
-    ip: IpAddr,
-    #[command(subcommand)]
-    command: TapoCommand,
+/// A device to connect to.
+struct DeviceSpec {
+    name: String,
+    address: DeviceAddress,
+    model: Model,
+}
+
+impl DeviceSpec {
+    fn from_config(name: &str, config: &Config) -> Result<Self> {
+        let device = config.devices.get(name).with_context(|| {
+            format!("{name:?} is neither an IP address nor a device in the config file")
+        })?;
+        Ok(Self {
+            name: name.to_string(),
+            address: device.address(),
+            model: device.model(),
+        })
+    }
 }
 
 #[derive(Subcommand, Clone, Debug)]
 enum TapoCommand {
     /// Take a measurement of current power consumption over multiple samples.
-    Measure,
+    Measure {
+        /// How many samples to take.
+        #[arg(long, default_value_t = MEASUREMENT_SAMPLE_COUNT)]
+        samples: usize,
+        /// Keep sampling for this long instead of a fixed number of samples, e.g. `30s` or `5m`.
+        #[arg(long, value_parser = humantime::parse_duration, conflicts_with = "samples")]
+        duration: Option<Duration>,
+        /// Keep sampling until the standard error of the mean drops below this tolerance, given
+        /// either in Watts (`0.5W`) or relative to the mean (`1%`).
+        #[arg(long, value_name = "TOLERANCE", conflicts_with_all = ["samples", "duration"])]
+        until_stable: Option<Tolerance>,
+        /// Give up on --until-stable after this long.
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "5m", requires = "until_stable")]
+        max_duration: Duration,
+        /// Time between two samples. The device doesn't update its reading more often than once a
+        /// second. [default: 1s, or `interval` from the config file]
+        #[arg(long, value_parser = humantime::parse_duration)]
+        interval: Option<Duration>,
+        /// [default: human, or `format` from the config file]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
     /// Continuously monitor momentary power consumption from your terminal.
-    Monitor,
+    Monitor {
+        /// Draw a live plot, or stream one record per sample to stdout. Streams NDJSON by default
+        /// when stdout is not a terminal.
+        #[arg(long, value_enum)]
+        output: Option<MonitorOutput>,
+        /// Also show the summed power of all devices, labelled `total` in streamed records.
+        #[arg(long)]
+        total: bool,
+        /// Also write the readings and what the devices say about themselves to this NDJSON
+        /// file, to look at them again later with `replay`.
+        #[arg(long, value_name = "FILE")]
+        record: Option<PathBuf>,
+    },
+    /// Plot a session recorded with `monitor --record` again, without the devices.
+    Replay {
+        recording: PathBuf,
+        /// How much faster than in real time to replay, e.g. `10x`.
+        #[arg(long, default_value = "1x")]
+        speed: Speed,
+        /// Also show the summed power of all devices.
+        #[arg(long)]
+        total: bool,
+    },
+    /// Compute statistics over a trace recorded with `monitor --record`, `monitor --output ndjson`
+    /// or `csv`, or `measure --format csv`, one device at a time.
+    Analyze {
+        trace: PathBuf,
+        /// Only look at readings from this time on, e.g. `2025-06-01 18:00` in local time or
+        /// `2025-06-01T18:00:00Z`.
+        #[arg(long, value_name = "TIME", value_parser = analyze::parse_time)]
+        from: Option<DateTime<Utc>>,
+        /// Only look at readings before this time.
+        #[arg(long, value_name = "TIME", value_parser = analyze::parse_time)]
+        to: Option<DateTime<Utc>>,
+        /// Count the load as on above this many Watts for the duty cycle. [default: halfway
+        /// between the minimum and maximum]
+        #[arg(long, value_name = "WATTS")]
+        on_above: Option<f64>,
+        /// Also report how long the power was above this many Watts.
+        #[arg(long, value_name = "WATTS")]
+        threshold: Option<f64>,
+        /// [default: human, or `format` from the config file]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
+    /// Keep reading from the devices in the background and serve what they read to Prometheus,
+    /// publish it over MQTT or write it to InfluxDB.
+    #[cfg(feature = "serve")]
+    Serve {
+        /// Time between two readings of the devices. [default: 1s, or `interval` from the config
+        /// file]
+        #[arg(long, value_parser = humantime::parse_duration)]
+        interval: Option<Duration>,
+        #[command(flatten)]
+        outputs: Box<ServeArgs>,
+    },
+    /// Keep reading from the devices and store every reading in a SQLite database, see `query`.
+    /// Readings are aggregated per minute and per hour as they age.
+    #[cfg(feature = "history")]
+    Log {
+        /// The database to write to, created if needed.
+        #[arg(long, value_name = "FILE")]
+        db: PathBuf,
+        /// How long to keep every single reading.
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "7days")]
+        keep_raw: Duration,
+        /// How long to keep the per-minute aggregates. Per-hour aggregates are kept forever.
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "90days")]
+        keep_minutes: Duration,
+        /// Time between two readings of the devices. [default: 1s, or `interval` from the config
+        /// file]
+        #[arg(long, value_parser = humantime::parse_duration)]
+        interval: Option<Duration>,
+    },
+    /// Print the readings stored by `log`, of all devices unless some are given by name.
+    #[cfg(feature = "history")]
+    Query {
+        /// The database `log` writes to.
+        #[arg(long, value_name = "FILE")]
+        db: PathBuf,
+        /// Only readings from this time on, e.g. `2025-06-01 18:00` in local time or
+        /// `2025-06-01T18:00:00Z`. [default: a day before --to]
+        #[arg(long, value_name = "TIME", value_parser = analyze::parse_time)]
+        from: Option<DateTime<Utc>>,
+        /// Only readings before this time. [default: now]
+        #[arg(long, value_name = "TIME", value_parser = analyze::parse_time)]
+        to: Option<DateTime<Utc>>,
+        /// [default: the finest kept for the whole time range, raw up to 6 hours and per-minute
+        /// up to 7 days]
+        #[arg(long, value_enum)]
+        resolution: Option<Resolution>,
+        /// [default: human, or `format` from the config file]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
+    /// Keep sessions to the devices, all of those in the config file unless some are given, and
+    /// serve what they read over HTTP as JSON. Other commands can read through it with --daemon
+    /// instead of each logging in to the devices, see the README for the API.
+    #[cfg(feature = "daemon")]
+    Daemon {
+        /// Where to serve the API.
+        #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1:9585")]
+        listen: SocketAddr,
+        /// How long to keep readings for `/devices/{id}/history`.
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "1h")]
+        history: Duration,
+        /// Time between two readings of the devices. [default: 1s, or `interval` from the config
+        /// file]
+        #[arg(long, value_parser = humantime::parse_duration)]
+        interval: Option<Duration>,
+    },
+    /// Run a command and report the energy it consumed, e.g. `run -- cargo build`.
+    Run {
+        /// How many samples to take before starting the command to establish the idle power
+        /// consumption. Use 0 to skip that.
+        #[arg(long, default_value_t = MEASUREMENT_SAMPLE_COUNT)]
+        baseline_samples: usize,
+        /// Time between two samples. [default: 1s, or `interval` from the config file]
+        #[arg(long, value_parser = humantime::parse_duration)]
+        interval: Option<Duration>,
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Estimate the cost of what the device consumed today, this month and earlier this year,
+    /// using its own energy history. Needs --price-per-kwh or --tariff.
+    Cost,
+    /// Check step by step whether we can read from the device, and suggest fixes for what's
+    /// wrong. Useful when setting up a new plug or machine.
+    Doctor,
+    /// List the Tapo devices on the local network.
+    Discover {
+        /// How long to wait for answers.
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "3s")]
+        wait: Duration,
+    },
+}
+
+impl TapoCommand {
+    fn supports_several_devices(&self) -> bool {
+        match self {
+            Self::Monitor { .. } | Self::Doctor => true,
+            #[cfg(feature = "serve")]
+            Self::Serve { .. } => true,
+            #[cfg(feature = "history")]
+            Self::Log { .. } | Self::Query { .. } => true,
+            #[cfg(feature = "daemon")]
+            Self::Daemon { .. } => true,
+            _ => false,
+        }
+    }
 }
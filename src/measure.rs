@@ -0,0 +1,486 @@
+use crate::{
+    energy::{integrate, is_discrepancy},
+    power_source::PowerSource,
+    tariff::{Tariff, format_cost},
+};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize, Serializer, ser::SerializeMap};
+use std::{
+    fmt, io,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::time::{MissedTickBehavior, interval};
+
+/// The standard error of the mean is meaningless for very few samples, so we never consider a
+/// measurement stable before having at least this many.
+const MIN_STABLE_SAMPLE_COUNT: usize = 5;
+
+/// When to stop taking samples.
+#[derive(Clone, Copy, Debug)]
+pub enum StopCondition {
+    AfterSamples(usize),
+    /// Once the standard error of the mean drops below the tolerance, or after `max_duration`.
+    WhenStable {
+        tolerance: Tolerance,
+        max_duration: Duration,
+    },
+}
+
+/// How precisely we want to know the mean power, e.g. `0.5W` or `1%`.
+#[derive(Clone, Copy, Debug)]
+pub enum Tolerance {
+    Watts(f32),
+    Percent(f32),
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+    /// One row per sample, repeating the summary columns on each.
+    Csv,
+    Toml,
+}
+
+/// Renders `items` as TOML, which has no top-level arrays, as an array of tables named `key`.
+pub fn toml_list<T: Serialize>(key: &str, items: &[T]) -> Result<String> {
+    struct List<'a, T>(&'a str, &'a [T]);
+
+    impl<T: Serialize> Serialize for List<'_, T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut map = serializer.serialize_map(Some(1))?;
+            map.serialize_entry(self.0, self.1)?;
+            map.end()
+        }
+    }
+
+    Ok(toml::to_string(&List(key, items))?)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Sample {
+    pub timestamp: DateTime<Utc>,
+    pub watts: u64,
+}
+
+/// The outcome of a measurement, serialized as is by the machine-readable output formats. Only
+/// ever add fields to it, scripts depend on its shape.
+#[derive(Debug, Serialize)]
+pub struct Measurement {
+    pub tool_version: &'static str,
+    pub device: String,
+    pub sample_interval_s: f64,
+    pub sample_count: usize,
+    pub mean_w: f32,
+    pub stddev_w: f32,
+    pub min_w: u64,
+    pub max_w: u64,
+    /// Energy consumed between the first and the last sample.
+    pub energy_wh: f64,
+    /// How much the device's own energy counter increased during the measurement, for comparison.
+    /// Only counts whole Watt hours, and is missing if the counter was reset meanwhile.
+    pub device_counter_wh: Option<u64>,
+    pub samples: Vec<Sample>,
+    /// What `energy_wh` cost, if a tariff is configured.
+    pub cost: Option<f64>,
+    pub currency: Option<String>,
+}
+
+/// A [`Measurement`] flattened into a single CSV row per sample.
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    tool_version: &'a str,
+    device: &'a str,
+    sample_interval_s: f64,
+    sample_count: usize,
+    mean_w: f32,
+    stddev_w: f32,
+    min_w: u64,
+    max_w: u64,
+    energy_wh: f64,
+    device_counter_wh: Option<u64>,
+    timestamp: DateTime<Utc>,
+    watts: u64,
+    cost: Option<f64>,
+    currency: Option<&'a str>,
+}
+
+impl StopCondition {
+    /// Stops once stable within `until_stable` if given, otherwise after as many samples as fit in
+    /// `duration` at `interval`, or else after `samples`. Always takes at least one sample.
+    pub fn new(
+        samples: usize,
+        duration: Option<Duration>,
+        until_stable: Option<Tolerance>,
+        max_duration: Duration,
+        interval: Duration,
+    ) -> Self {
+        match (until_stable, duration) {
+            (Some(tolerance), _) => StopCondition::WhenStable {
+                tolerance,
+                max_duration,
+            },
+            (None, Some(duration)) => StopCondition::AfterSamples(
+                ((duration.as_secs_f64() / interval.as_secs_f64()).ceil() as usize).max(1),
+            ),
+            (None, None) => StopCondition::AfterSamples(samples.max(1)),
+        }
+    }
+}
+
+impl Measurement {
+    pub fn new(
+        device: String,
+        sample_interval: Duration,
+        samples: Vec<Sample>,
+        device_counter_wh: Option<u64>,
+        tariff: Option<Tariff>,
+    ) -> Self {
+        let watts: Vec<u64> = samples.iter().map(|sample| sample.watts).collect();
+        let (mean, standard_deviation) = mean_and_standard_deviation(&watts);
+        let currency = tariff.as_ref().map(|tariff| tariff.currency.clone());
+        let energy = integrate(&samples, tariff);
+
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION"),
+            device,
+            sample_interval_s: sample_interval.as_secs_f64(),
+            sample_count: samples.len(),
+            mean_w: mean,
+            stddev_w: standard_deviation,
+            min_w: *watts.iter().min().expect("we obtained samples"),
+            max_w: *watts.iter().max().expect("we obtained samples"),
+            energy_wh: energy.watt_hours(),
+            device_counter_wh,
+            samples,
+            cost: energy.cost(),
+            currency,
+        }
+    }
+
+    pub fn print(&self, format: OutputFormat) -> Result<()> {
+        match format {
+            OutputFormat::Human => {
+                let watts: Vec<u64> = self.samples.iter().map(|sample| sample.watts).collect();
+                println!("avg: {:.1} W +-{:.1} W", self.mean_w, self.stddev_w);
+                println!("min: {} W", self.min_w);
+                println!("max: {} W", self.max_w);
+                println!("energy: {:.4} Wh", self.energy_wh);
+                if let Some(cost) = self.cost {
+                    let currency = self.currency.as_deref().unwrap_or_default();
+                    println!("cost: {}", format_cost(cost, currency));
+                }
+                if let Some(device_counter) = self.device_counter_wh {
+                    println!("device energy counter: +{device_counter} Wh");
+                    if is_discrepancy(self.energy_wh, device_counter) {
+                        eprintln!(
+                            "warning: the device counted {device_counter} Wh, but the samples add \
+                                up to {:.2} Wh",
+                            self.energy_wh
+                        );
+                    }
+                }
+                println!("samples: {watts:?}");
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+            OutputFormat::Toml => print!("{}", toml::to_string(self)?),
+            OutputFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(io::stdout());
+                for sample in &self.samples {
+                    writer.serialize(CsvRow {
+                        tool_version: self.tool_version,
+                        device: &self.device,
+                        sample_interval_s: self.sample_interval_s,
+                        sample_count: self.sample_count,
+                        mean_w: self.mean_w,
+                        stddev_w: self.stddev_w,
+                        min_w: self.min_w,
+                        max_w: self.max_w,
+                        energy_wh: self.energy_wh,
+                        device_counter_wh: self.device_counter_wh,
+                        timestamp: sample.timestamp,
+                        watts: sample.watts,
+                        cost: self.cost,
+                        currency: self.currency.as_deref(),
+                    })?;
+                }
+                writer.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Tolerance {
+    fn is_met(&self, samples: &[u64]) -> bool {
+        if samples.len() < MIN_STABLE_SAMPLE_COUNT {
+            return false;
+        }
+
+        let (mean, _) = mean_and_standard_deviation(samples);
+        let standard_error = standard_error(samples);
+        match *self {
+            Tolerance::Watts(watts) => standard_error <= watts,
+            Tolerance::Percent(percent) => standard_error <= mean * percent / 100.0,
+        }
+    }
+}
+
+impl FromStr for Tolerance {
+    type Err = anyhow::Error;
+
+    fn from_str(tolerance: &str) -> Result<Self> {
+        let tolerance = tolerance
+            .trim()
+            .trim_start_matches("+-")
+            .trim_start_matches('±');
+        let parse = |number: &str| -> Result<f32> {
+            let number: f32 = number.trim().parse().context("Expected e.g. 0.5W or 1%")?;
+            if number.is_nan() || number <= 0.0 {
+                bail!("Tolerance must be positive");
+            }
+            Ok(number)
+        };
+
+        if let Some(percent) = tolerance.strip_suffix('%') {
+            Ok(Tolerance::Percent(parse(percent)?))
+        } else {
+            let watts = tolerance.strip_suffix(['W', 'w']).unwrap_or(tolerance);
+            Ok(Tolerance::Watts(parse(watts)?))
+        }
+    }
+}
+
+impl fmt::Display for Tolerance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tolerance::Watts(watts) => write!(f, "{watts} W"),
+            Tolerance::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+pub async fn get_samples(
+    device: &dyn PowerSource,
+    stop: StopCondition,
+    sampling_interval: Duration,
+) -> Result<Vec<Sample>> {
+    let progress_bar = match stop {
+        StopCondition::AfterSamples(count) => {
+            let style = ProgressStyle::with_template(
+                "obtaining samples... [{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7}",
+            )
+            .expect("valid style");
+            ProgressBar::new(count as u64).with_style(style)
+        }
+        StopCondition::WhenStable { tolerance, .. } => {
+            let style = ProgressStyle::with_template(
+                "obtaining samples until stable... [{elapsed}] {spinner} {pos:>7} {msg}",
+            )
+            .expect("valid style");
+            ProgressBar::no_length()
+                .with_style(style)
+                .with_message(format!("(want ±{tolerance})"))
+        }
+    };
+
+    // Unlike sleeping between samples, this accounts for the time the requests themselves take.
+    let mut ticks = interval(sampling_interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let started = Instant::now();
+    let mut samples = Vec::new();
+    let mut readings = Vec::new();
+    loop {
+        let done = match stop {
+            StopCondition::AfterSamples(count) => readings.len() >= count,
+            StopCondition::WhenStable {
+                tolerance,
+                max_duration,
+            } => {
+                // Always take at least two samples so that there is some standard error to report.
+                readings.len() >= 2
+                    && (tolerance.is_met(&readings) || started.elapsed() >= max_duration)
+            }
+        };
+        if done {
+            break;
+        }
+
+        ticks.tick().await;
+        let watts = device.current_power().await?;
+        readings.push(watts);
+        samples.push(Sample {
+            timestamp: Utc::now(),
+            watts,
+        });
+        progress_bar.inc(1);
+        if let StopCondition::WhenStable { tolerance, .. } = stop
+            && readings.len() >= 2
+        {
+            let standard_error = standard_error(&readings);
+            progress_bar.set_message(format!("±{standard_error:.2} W (want ±{tolerance})"));
+        }
+    }
+
+    progress_bar.finish_and_clear();
+
+    if let StopCondition::WhenStable { tolerance, .. } = stop {
+        let elapsed = humantime::format_duration(Duration::from_secs(started.elapsed().as_secs()));
+        if tolerance.is_met(&readings) {
+            eprintln!("stable after {} samples ({elapsed})", readings.len());
+        } else {
+            eprintln!(
+                "warning: not stable within ±{tolerance} after {} samples ({elapsed}), \
+                    the mean is only known to ±{:.2} W",
+                readings.len(),
+                standard_error(&readings)
+            );
+        }
+    }
+
+    Ok(samples)
+}
+
+pub fn mean_and_standard_deviation(samples: &[u64]) -> (f32, f32) {
+    let len = samples.len() as f32;
+    let samples_f32 = samples.iter().map(|sample| *sample as f32);
+    let mean = samples_f32.clone().sum::<f32>() / len;
+    let variance: f32 = samples_f32
+        .map(|sample| (sample - mean).powi(2))
+        .sum::<f32>()
+        / len;
+
+    (mean, variance.sqrt())
+}
+
+/// Standard error of the mean, i.e. how far off the mean of our samples likely is from the actual
+/// mean power.
+fn standard_error(samples: &[u64]) -> f32 {
+    let (_, standard_deviation) = mean_and_standard_deviation(samples);
+    // Bessel's correction, the samples are just that and not the whole population.
+    standard_deviation / ((samples.len() - 1) as f32).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn sample_count(stop: StopCondition) -> usize {
+        match stop {
+            StopCondition::AfterSamples(count) => count,
+            StopCondition::WhenStable { .. } => panic!("expected a sample count, got {stop:?}"),
+        }
+    }
+
+    #[test]
+    fn stops_after_samples() {
+        assert_eq!(
+            sample_count(StopCondition::new(10, None, None, SECOND, SECOND)),
+            10
+        );
+        // Zero samples would leave nothing to average.
+        assert_eq!(
+            sample_count(StopCondition::new(0, None, None, SECOND, SECOND)),
+            1
+        );
+    }
+
+    #[test]
+    fn stops_after_duration() {
+        let after = |duration: Duration, interval: Duration| {
+            sample_count(StopCondition::new(
+                10,
+                Some(duration),
+                None,
+                SECOND,
+                interval,
+            ))
+        };
+        assert_eq!(after(Duration::from_secs(30), SECOND), 30);
+        assert_eq!(after(Duration::from_secs(30), Duration::from_secs(2)), 15);
+        // Partial intervals still get a sample.
+        assert_eq!(after(Duration::from_millis(2500), SECOND), 3);
+        assert_eq!(after(Duration::from_millis(100), SECOND), 1);
+        assert_eq!(after(Duration::ZERO, SECOND), 1);
+    }
+
+    #[test]
+    fn waits_until_stable_rather_than_for_a_duration() {
+        let stop = StopCondition::new(
+            10,
+            Some(Duration::from_secs(30)),
+            Some(Tolerance::Watts(0.5)),
+            Duration::from_secs(60),
+            SECOND,
+        );
+        assert!(matches!(
+            stop,
+            StopCondition::WhenStable { max_duration, .. } if max_duration == Duration::from_secs(60)
+        ));
+    }
+
+    #[test]
+    fn parses_tolerances() {
+        let watts = |tolerance: &str| match tolerance.parse().unwrap() {
+            Tolerance::Watts(watts) => watts,
+            Tolerance::Percent(_) => panic!("{tolerance:?} is not in Watts"),
+        };
+        let percent = |tolerance: &str| match tolerance.parse().unwrap() {
+            Tolerance::Percent(percent) => percent,
+            Tolerance::Watts(_) => panic!("{tolerance:?} is not in percent"),
+        };
+
+        assert_eq!(watts("0.5W"), 0.5);
+        assert_eq!(watts("0.5w"), 0.5);
+        assert_eq!(watts("2"), 2.0);
+        assert_eq!(watts("+-0.5W"), 0.5);
+        assert_eq!(watts("±0.5 W"), 0.5);
+        assert_eq!(percent("1%"), 1.0);
+        assert_eq!(percent(" ±2.5 % "), 2.5);
+    }
+
+    #[test]
+    fn rejects_invalid_tolerances() {
+        for tolerance in [
+            "", "W", "%", "0W", "0%", "-1%", "NaN", "lots", "1 kW", "1%W",
+        ] {
+            assert!(
+                tolerance.parse::<Tolerance>().is_err(),
+                "{tolerance:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn stable_readings_converge() {
+        let samples = [50; MIN_STABLE_SAMPLE_COUNT];
+        assert!(Tolerance::Watts(0.1).is_met(&samples));
+        assert!(Tolerance::Percent(0.1).is_met(&samples));
+        // Too few samples to tell, however stable.
+        assert!(!Tolerance::Watts(0.1).is_met(&samples[1..]));
+    }
+
+    #[test]
+    fn noisy_readings_converge_with_more_samples() {
+        // Alternating between 40 and 60 W, a standard deviation of 10 W.
+        let samples: Vec<u64> = (0..401).map(|index| 40 + index % 2 * 20).collect();
+        // The standard error is 10 / sqrt(n - 1).
+        assert!(!Tolerance::Watts(2.0).is_met(&samples[..6]));
+        assert!(Tolerance::Watts(2.0).is_met(&samples[..27]));
+        assert!(!Tolerance::Watts(1.0).is_met(&samples[..27]));
+        assert!(Tolerance::Watts(1.0).is_met(&samples));
+        // Relative to the mean of 50 W.
+        assert!(!Tolerance::Percent(2.0).is_met(&samples[..27]));
+        assert!(Tolerance::Percent(2.0).is_met(&samples));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{polling::DeviceState, serve::Output};
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use axum::{
+    Router, extract::State, http::header::CONTENT_TYPE, response::IntoResponse, routing::get,
+};
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+/// Whether and where to serve metrics to Prometheus.
+#[derive(clap::Args, Clone, Debug)]
+pub struct MetricsArgs {
+    /// Serve the readings, energy counters and connection health of the devices as Prometheus
+    /// metrics at `/metrics`.
+    #[arg(long, group = "outputs")]
+    metrics: bool,
+    /// Where to serve the metrics.
+    #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1:9584")]
+    listen: SocketAddr,
+}
+
+/// Serves the states of the devices in the background.
+struct Exporter {
+    server: JoinHandle<Result<()>>,
+}
+
+impl MetricsArgs {
+    /// Starts serving what we know about the devices if asked to.
+    pub async fn output(
+        &self,
+        devices: &Arc<Mutex<Vec<DeviceState>>>,
+    ) -> Result<Option<Box<dyn Output>>> {
+        if !self.metrics {
+            return Ok(None);
+        }
+        let listener = TcpListener::bind(self.listen)
+            .await
+            .with_context(|| format!("Listening on {}", self.listen))?;
+        eprintln!(
+            "Serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+        Ok(Some(Box::new(Exporter {
+            server: tokio::spawn(serve(listener, devices.clone())),
+        })))
+    }
+}
+
+#[async_trait]
+impl Output for Exporter {
+    /// Prometheus scrapes the states as they are, only makes sure that they are still served.
+    fn publish(&mut self, _: &[DeviceState], _: DateTime<Utc>) -> Result<()> {
+        if let Some(result) = (&mut self.server).now_or_never() {
+            result??;
+            bail!("Stopped serving metrics");
+        }
+        Ok(())
+    }
+}
+
+/// Serves what we know about the devices to Prometheus at `/metrics`.
+async fn serve(listener: TcpListener, devices: Arc<Mutex<Vec<DeviceState>>>) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(devices);
+    axum::serve(listener, app).await.context("Serving metrics")
+}
+
+async fn metrics(State(devices): State<Arc<Mutex<Vec<DeviceState>>>>) -> impl IntoResponse {
+    let devices = devices.lock().unwrap();
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render(&devices),
+    )
+}
+
+/// The Prometheus text exposition format.
+fn render(devices: &[DeviceState]) -> String {
+    let mut out = String::new();
+    family(
+        &mut out,
+        devices,
+        "tapo_up",
+        "gauge",
+        "Whether the device answered the last time it was read.",
+        |device| Some(if device.up { 1.0 } else { 0.0 }),
+    );
+    family(
+        &mut out,
+        devices,
+        "tapo_power_watts",
+        "gauge",
+        "Momentary power consumption.",
+        |device| device.watts.map(|watts| watts as f64),
+    );
+    family(
+        &mut out,
+        devices,
+        "tapo_energy_watt_hours_total",
+        "counter",
+        "Energy consumed since `serve` started, integrated from the power readings.",
+        |device| Some(device.energy_meter.watt_hours()),
+    );
+    family(
+        &mut out,
+        devices,
+        "tapo_today_energy_watt_hours",
+        "gauge",
+        "Energy consumed today, as counted by the device.",
+        |device| Some(device.energy_usage.as_ref()?.today_energy as f64),
+    );
+    family(
+        &mut out,
+        devices,
+        "tapo_month_energy_watt_hours",
+        "gauge",
+        "Energy consumed this month, as counted by the device.",
+        |device| Some(device.energy_usage.as_ref()?.month_energy as f64),
+    );
+    family(
+        &mut out,
+        devices,
+        "tapo_wifi_rssi_dbm",
+        "gauge",
+        "Wi-Fi signal strength.",
+        |device| Some(device.info.as_ref()?.rssi? as f64),
+    );
+    family(
+        &mut out,
+        devices,
+        "tapo_on_time_seconds",
+        "gauge",
+        "How long the plug has been switched on.",
+        |device| Some(device.info.as_ref()?.on_time?.as_secs_f64()),
+    );
+    family(
+        &mut out,
+        devices,
+        "tapo_scrape_errors_total",
+        "counter",
+        "How many times reading from the device failed, even after logging in again.",
+        |device| Some(device.errors as f64),
+    );
+
+    writeln!(
+        out,
+        "# HELP tapo_device_info The device's model and nickname."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE tapo_device_info gauge").unwrap();
+    for device in devices {
+        if let Some(info) = &device.info {
+            writeln!(
+                out,
+                "tapo_device_info{{device=\"{}\",model=\"{}\",nickname=\"{}\"}} 1",
+                escape(&device.name),
+                escape(&info.model),
+                escape(&info.nickname)
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// Writes a metric with one sample per device, leaving out those without a `value`.
+fn family(
+    out: &mut String,
+    devices: &[DeviceState],
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&DeviceState) -> Option<f64>,
+) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+    for device in devices {
+        if let Some(value) = value(device) {
+            writeln!(out, "{name}{{device=\"{}\"}} {value}", escape(&device.name)).unwrap();
+        }
+    }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
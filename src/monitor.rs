@@ -0,0 +1,577 @@
+use crate::{
+    TAPO_TEMPORAL_RESOLUTION,
+    connection::{Connection, print_outages},
+    energy::{EnergyMeter, counter_increase, is_discrepancy},
+    measure::Sample,
+    power_source::{Device, DeviceInfo, EnergyUsage},
+    recording::{Recorder, Session, Speed},
+    tariff::Tariff,
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use console::{Term, style};
+use futures::future::{join_all, try_join_all};
+use rgb::RGB8;
+use serde::Serialize;
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+use textplots::{Chart, ColorPlot, LabelBuilder, LabelFormat, Plot, Shape};
+use tokio::{
+    select,
+    signal::ctrl_c,
+    time::{MissedTickBehavior, interval, sleep},
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum MonitorOutput {
+    /// A live chart in the terminal.
+    Plot,
+    /// One JSON object per line and sample.
+    Ndjson,
+    /// One CSV row per sample, with a header.
+    Csv,
+}
+
+/// How the summed readings of all devices are labelled in streamed records.
+const TOTAL: &str = "total";
+
+/// A single streamed sample.
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: DateTime<Utc>,
+    device: &'a str,
+    /// Missing while the device can't be reached.
+    watts: Option<u64>,
+    /// Energy consumed since monitoring started.
+    energy_wh: f64,
+    /// What `energy_wh` cost, if a tariff is configured.
+    cost: Option<f64>,
+}
+
+/// Colors of the plotted series, as true color for the chart and the closest 256 color terminal
+/// palette entry for the legend.
+const SERIES_COLORS: [(RGB8, u8); 6] = [
+    (RGB8 { r: 255, g: 0, b: 0 }, 196),
+    (RGB8 { r: 0, g: 255, b: 0 }, 46),
+    (
+        RGB8 {
+            r: 0,
+            g: 135,
+            b: 255,
+        },
+        33,
+    ),
+    (
+        RGB8 {
+            r: 255,
+            g: 255,
+            b: 0,
+        },
+        226,
+    ),
+    (
+        RGB8 {
+            r: 255,
+            g: 0,
+            b: 255,
+        },
+        201,
+    ),
+    (
+        RGB8 {
+            r: 0,
+            g: 255,
+            b: 255,
+        },
+        51,
+    ),
+];
+
+/// A plotted device and what we know about it so far.
+struct Series {
+    name: String,
+    info: DeviceInfo,
+    /// The device's own energy counters, unless replaying a recording.
+    counters: Option<Counters>,
+    energy_meter: EnergyMeter,
+    /// Readings by time, with gaps while the device couldn't be reached.
+    points: Vec<(f32, Option<f32>)>,
+}
+
+struct Counters {
+    initial: EnergyUsage,
+    current: EnergyUsage,
+    /// What we had integrated by the time we last read the counters, to compare them.
+    energy_at_refresh: f64,
+}
+
+/// A live chart of the readings of one or more devices, with a footer summarizing them.
+struct LivePlot {
+    all_series: Vec<Series>,
+    show_total: bool,
+    total_energy_meter: EnergyMeter,
+    total_points: Vec<(f32, Option<f32>)>,
+    /// The latest readings, in the order of `all_series`.
+    readings: Vec<Option<u64>>,
+    tariff: Option<Tariff>,
+    term: Term,
+}
+
+/// Plots or streams readings from the devices until interrupted, and then lists when any of them
+/// couldn't be reached. Devices that stop answering leave gaps and are reconnected to. With
+/// `record`, also writes the readings to that file for [`replay`].
+pub async fn monitor(
+    devices: Vec<Device>,
+    show_total: bool,
+    output: MonitorOutput,
+    tariff: Option<Tariff>,
+    record: Option<PathBuf>,
+) -> Result<()> {
+    let mut connections: Vec<Connection> = devices.into_iter().map(Connection::new).collect();
+    let mut recorder = match record {
+        Some(path) => Some(Recorder::create(&path, &connections).await?),
+        None => None,
+    };
+    let monitoring = async {
+        match output {
+            MonitorOutput::Plot => {
+                plot(&mut connections, show_total, tariff, recorder.as_mut()).await
+            }
+            MonitorOutput::Ndjson | MonitorOutput::Csv => {
+                stream(
+                    &mut connections,
+                    show_total,
+                    output,
+                    tariff,
+                    recorder.as_mut(),
+                )
+                .await
+            }
+        }
+    };
+    let result = select! {
+        result = monitoring => result,
+        _ = ctrl_c() => Ok(()),
+    };
+
+    print_outages(&connections);
+    result
+}
+
+/// Plots a recorded session as `monitor` did, `speed` times faster than it was recorded. Leaves
+/// the final chart on screen.
+pub async fn replay(
+    session: Session,
+    speed: Speed,
+    show_total: bool,
+    tariff: Option<Tariff>,
+) -> Result<()> {
+    // There are no connections to report on.
+    let no_statuses = vec![None; session.devices.len()];
+    let all_series = session
+        .devices
+        .into_iter()
+        .map(|(name, info)| Series::new(name, info, None, tariff.clone()))
+        .collect();
+    let mut live_plot = LivePlot::new(all_series, show_total, tariff);
+
+    let replaying = async {
+        let mut previous: Option<DateTime<Utc>> = None;
+        for (timestamp, readings) in session.ticks {
+            if let Some(previous) = previous {
+                let elapsed = (timestamp - previous).to_std().unwrap_or_default();
+                sleep(speed.scale(elapsed)).await;
+            }
+            previous = Some(timestamp);
+
+            live_plot.add(timestamp, readings);
+            live_plot.draw(&no_statuses);
+        }
+    };
+    select! {
+        _ = replaying => {},
+        _ = ctrl_c() => {},
+    }
+    Ok(())
+}
+
+// Inspired by https://github.com/loony-bean/textplots-rs/blob/master/examples/liveplot.rs.
+async fn plot(
+    connections: &mut [Connection],
+    show_total: bool,
+    tariff: Option<Tariff>,
+    mut recorder: Option<&mut Recorder>,
+) -> Result<()> {
+    // The device's own energy counters only change once in a while, no need to poll them often.
+    const ENERGY_USAGE_REFRESH_PERIOD: usize = 60;
+
+    let infos = try_join_all(
+        connections
+            .iter()
+            .map(|connection| connection.device.source.device_info()),
+    )
+    .await?;
+    let energy_usages = try_join_all(
+        connections
+            .iter()
+            .map(|connection| connection.device.source.energy_usage()),
+    )
+    .await?;
+    let all_series = connections
+        .iter()
+        .zip(infos)
+        .zip(energy_usages)
+        .map(|((connection, info), energy_usage)| {
+            let counters = Counters {
+                initial: energy_usage.clone(),
+                current: energy_usage,
+                energy_at_refresh: 0.0,
+            };
+            Series::new(
+                connection.device.name.clone(),
+                info,
+                Some(counters),
+                tariff.clone(),
+            )
+        })
+        .collect();
+    let mut live_plot = LivePlot::new(all_series, show_total, tariff);
+
+    let mut iteration = 0;
+    loop {
+        iteration += 1;
+
+        // Get the next samples, from all devices at once.
+        let readings = read_current_power(connections).await;
+        let timestamp = Utc::now();
+        if let Some(recorder) = recorder.as_deref_mut() {
+            recorder.record(timestamp, &readings)?;
+        }
+        live_plot.add(timestamp, readings);
+
+        if iteration % ENERGY_USAGE_REFRESH_PERIOD == 0 {
+            let energy_usages = join_all(
+                connections
+                    .iter()
+                    .map(|connection| connection.energy_usage()),
+            )
+            .await;
+            live_plot.refresh_counters(energy_usages);
+        }
+
+        let statuses: Vec<Option<String>> = connections.iter().map(Connection::status).collect();
+        live_plot.draw(&statuses);
+
+        sleep(TAPO_TEMPORAL_RESOLUTION).await;
+    }
+}
+
+impl Series {
+    fn new(
+        name: String,
+        info: DeviceInfo,
+        counters: Option<Counters>,
+        tariff: Option<Tariff>,
+    ) -> Self {
+        Self {
+            name,
+            info,
+            counters,
+            energy_meter: EnergyMeter::new(tariff),
+            points: Vec::new(),
+        }
+    }
+}
+
+impl LivePlot {
+    const WIDTH: usize = 100;
+
+    /// Clears the terminal to draw on it.
+    fn new(all_series: Vec<Series>, show_total: bool, tariff: Option<Tariff>) -> Self {
+        let term = Term::stdout();
+        term.clear_screen().unwrap();
+        Self {
+            readings: vec![None; all_series.len()],
+            all_series,
+            show_total,
+            total_energy_meter: EnergyMeter::new(tariff.clone()),
+            total_points: Vec::new(),
+            tariff,
+            term,
+        }
+    }
+
+    /// Takes in the readings of all devices at `timestamp`, `None` for those that couldn't be
+    /// reached.
+    fn add(&mut self, timestamp: DateTime<Utc>, readings: Vec<Option<u64>>) {
+        for (series, &watts) in self.all_series.iter_mut().zip(&readings) {
+            shift_in(&mut series.points, watts, Self::WIDTH);
+            // Gaps are bridged by interpolating between the readings around them.
+            if let Some(watts) = watts {
+                series.energy_meter.add(&Sample { timestamp, watts });
+            }
+        }
+        let total = total(&readings);
+        shift_in(&mut self.total_points, total, Self::WIDTH);
+        if let Some(watts) = total {
+            self.total_energy_meter.add(&Sample { timestamp, watts });
+        }
+        self.readings = readings;
+    }
+
+    /// Takes in the devices' own energy counters, `None` for the devices that couldn't be reached
+    /// which keep their last known counters.
+    fn refresh_counters(&mut self, energy_usages: Vec<Option<EnergyUsage>>) {
+        for (series, energy_usage) in self.all_series.iter_mut().zip(energy_usages) {
+            if let (Some(counters), Some(energy_usage)) = (&mut series.counters, energy_usage) {
+                counters.current = energy_usage;
+                counters.energy_at_refresh = series.energy_meter.watt_hours();
+            }
+        }
+    }
+
+    /// Redraws the chart and the footer, with the given statuses of the devices' connections.
+    fn draw(&self, statuses: &[Option<String>]) {
+        // Tell the series apart only when there are several.
+        let colored = self.all_series.len() > 1;
+
+        self.term.move_cursor_to(0, 0).unwrap();
+        draw(
+            &self.all_series,
+            self.show_total.then_some(&self.total_points[..]),
+            colored,
+            Self::WIDTH,
+        );
+
+        // The footer may be shorter than the last time around.
+        self.term.clear_to_end_of_screen().unwrap();
+        for (index, ((series, status), watts)) in self
+            .all_series
+            .iter()
+            .zip(statuses)
+            .zip(&self.readings)
+            .enumerate()
+        {
+            let prefix = if colored {
+                let color = SERIES_COLORS[index % SERIES_COLORS.len()].1;
+                format!("{}: ", style(&series.name).color256(color))
+            } else {
+                String::new()
+            };
+            let energy = series.energy_meter.watt_hours();
+            let cost = format_cost(&series.energy_meter, self.tariff.as_ref());
+
+            if let Some(status) = status {
+                println!("{prefix}{}", style(status).red());
+            }
+            let watts = format_watts(*watts);
+            let device_counter = series.counters.as_ref().map(|counters| {
+                let increase =
+                    counter_increase(counters.initial.today_energy, counters.current.today_energy);
+                let discrepancy = match increase {
+                    Some(increase) if is_discrepancy(counters.energy_at_refresh, increase) => {
+                        " (mismatch!)"
+                    }
+                    _ => "",
+                };
+                format!(
+                    ", device counter: {}{discrepancy}",
+                    increase.map_or("reset".to_string(), |wh| format!("+{wh} Wh"))
+                )
+            });
+            println!(
+                "{prefix}current power: {watts}, energy since start: {energy:.3} Wh{cost}{}",
+                device_counter.unwrap_or_default()
+            );
+            println!(
+                "{}{} ({})",
+                if colored { "  " } else { "" },
+                series.info.nickname,
+                series.info.model,
+            );
+        }
+        if self.show_total {
+            println!(
+                "total: current power: {}, energy since start: {:.3} Wh{}",
+                format_watts(total(&self.readings)),
+                self.total_energy_meter.watt_hours(),
+                format_cost(&self.total_energy_meter, self.tariff.as_ref())
+            );
+        }
+    }
+}
+
+/// Draws one line per device, colored if `colored`, and the total in the terminal's color.
+fn draw(
+    all_series: &[Series],
+    total_points: Option<&[(f32, Option<f32>)]>,
+    colored: bool,
+    width: usize,
+) {
+    // Each stretch between gaps is a line of its own, with the color of its series.
+    let series_segments: Vec<(usize, Vec<(f32, f32)>)> = all_series
+        .iter()
+        .enumerate()
+        .flat_map(|(index, series)| {
+            segments(&series.points)
+                .into_iter()
+                .map(move |segment| (index, segment))
+        })
+        .collect();
+    let total_segments = total_points.map(segments).unwrap_or_default();
+    let shapes: Vec<(usize, Shape)> = series_segments
+        .iter()
+        .map(|(index, segment)| (*index, Shape::Steps(segment)))
+        .collect();
+    let total_shapes: Vec<Shape> = total_segments
+        .iter()
+        .map(|segment| Shape::Steps(segment))
+        .collect();
+
+    let mut chart = Chart::new(200, 50, -(width as f32), 0.0);
+    let mut chart = chart
+        .x_label_format(LabelFormat::Custom(Box::new(|ts| match ts {
+            0.0 => "now".to_string(),
+            ts => format!("{ts:.0} seconds"),
+        })))
+        .y_label_format(LabelFormat::Custom(Box::new(|watts| format!("{watts} W"))));
+    for (index, shape) in &shapes {
+        chart = if colored {
+            chart.linecolorplot(shape, SERIES_COLORS[index % SERIES_COLORS.len()].0)
+        } else {
+            chart.lineplot(shape)
+        };
+    }
+    for total_shape in &total_shapes {
+        chart = chart.lineplot(total_shape);
+    }
+    chart.nice();
+}
+
+/// Appends a reading, or a gap, to a plotted series, shifting the older ones to the left.
+fn shift_in(points: &mut Vec<(f32, Option<f32>)>, watts: Option<u64>, width: usize) {
+    for point in points.iter_mut() {
+        point.0 -= 1.0;
+    }
+    if points.len() == width {
+        points.remove(0);
+    }
+    points.push((0., watts.map(|watts| watts as f32)));
+}
+
+/// Splits plotted points into the stretches without gaps.
+fn segments(points: &[(f32, Option<f32>)]) -> Vec<Vec<(f32, f32)>> {
+    points
+        .split(|(_, watts)| watts.is_none())
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            segment
+                .iter()
+                .filter_map(|&(time, watts)| Some((time, watts?)))
+                .collect()
+        })
+        .collect()
+}
+
+/// The sum of all readings, unless some are missing.
+fn total(readings: &[Option<u64>]) -> Option<u64> {
+    readings.iter().copied().sum()
+}
+
+/// A reading, or a dash while there is none.
+fn format_watts(watts: Option<u64>) -> String {
+    watts.map_or("-".to_string(), |watts| format!("{watts}W"))
+}
+
+fn format_cost(energy_meter: &EnergyMeter, tariff: Option<&Tariff>) -> String {
+    match (energy_meter.cost(), tariff) {
+        (Some(cost), Some(tariff)) => format!(" ({})", tariff.format(cost)),
+        _ => String::new(),
+    }
+}
+
+/// Reads from all devices at once, `None` for those that can't be reached.
+async fn read_current_power(connections: &mut [Connection]) -> Vec<Option<u64>> {
+    join_all(
+        connections
+            .iter_mut()
+            .map(|connection| connection.current_power()),
+    )
+    .await
+}
+
+/// Streams samples to stdout until interrupted or until whoever reads them goes away. Writes one
+/// record per device and sample, plus one for their sum with `show_total`. Records of devices that
+/// can't be reached have no `watts`.
+async fn stream(
+    connections: &mut [Connection],
+    show_total: bool,
+    output: MonitorOutput,
+    tariff: Option<Tariff>,
+    mut recorder: Option<&mut Recorder>,
+) -> Result<()> {
+    let mut ticks = interval(TAPO_TEMPORAL_RESOLUTION);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut csv_writer = csv::Writer::from_writer(io::stdout());
+    let mut energy_meters: Vec<EnergyMeter> = connections
+        .iter()
+        .map(|_| EnergyMeter::new(tariff.clone()))
+        .collect();
+    let mut total_energy_meter = EnergyMeter::new(tariff);
+    loop {
+        ticks.tick().await;
+        let readings = read_current_power(connections).await;
+        let timestamp = Utc::now();
+        if let Some(recorder) = recorder.as_deref_mut() {
+            recorder.record(timestamp, &readings)?;
+        }
+
+        let mut samples: Vec<(&str, Option<u64>, &mut EnergyMeter)> = connections
+            .iter()
+            .zip(readings.iter())
+            .zip(energy_meters.iter_mut())
+            .map(|((connection, &watts), energy_meter)| {
+                (connection.device.name.as_str(), watts, energy_meter)
+            })
+            .collect();
+        if show_total {
+            samples.push((TOTAL, total(&readings), &mut total_energy_meter));
+        }
+
+        for (device, watts, energy_meter) in samples {
+            if let Some(watts) = watts {
+                energy_meter.add(&Sample { timestamp, watts });
+            }
+            let record = Record {
+                timestamp,
+                device,
+                watts,
+                energy_wh: energy_meter.watt_hours(),
+                cost: energy_meter.cost(),
+            };
+
+            let written = match output {
+                MonitorOutput::Ndjson => {
+                    let mut stdout = io::stdout().lock();
+                    writeln!(stdout, "{}", serde_json::to_string(&record)?)
+                        .and_then(|_| stdout.flush())
+                }
+                MonitorOutput::Csv => csv_writer
+                    .serialize(&record)
+                    .map_err(io::Error::from)
+                    .and_then(|_| csv_writer.flush()),
+                MonitorOutput::Plot => unreachable!("plots are drawn by plot()"),
+            };
+
+            match written {
+                // E.g. piped into `head`, which exited.
+                Err(error) if error.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                result => result?,
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{
+    connection::Connection,
+    energy::EnergyMeter,
+    measure::Sample,
+    power_source::{DeviceInfo, EnergyUsage},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use std::{sync::Mutex, time::Duration};
+use tokio::time::{MissedTickBehavior, interval};
+
+/// What we know about a device so far, as served to Prometheus, published over MQTT, written to
+/// InfluxDB, logged by `log` and served by `daemon`.
+pub struct DeviceState {
+    pub name: String,
+    /// When the device was last read.
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Missing until the device first answered.
+    pub info: Option<DeviceInfo>,
+    pub energy_usage: Option<EnergyUsage>,
+    /// Missing while the device can't be reached.
+    pub watts: Option<u64>,
+    /// Energy consumed since we started, bridging gaps.
+    pub energy_meter: EnergyMeter,
+    pub up: bool,
+    pub errors: u64,
+}
+
+impl DeviceState {
+    /// Nothing known yet about any of the devices.
+    pub fn all(connections: &[Connection]) -> Vec<Self> {
+        connections
+            .iter()
+            .map(|connection| Self {
+                name: connection.device.name.clone(),
+                timestamp: None,
+                info: None,
+                energy_usage: None,
+                watts: None,
+                energy_meter: EnergyMeter::new(None),
+                up: false,
+                errors: 0,
+            })
+            .collect()
+    }
+}
+
+/// Reads from all devices every so often, keeping `states` up to date and passing them to
+/// `on_reading` every time.
+pub async fn poll(
+    connections: &mut [Connection],
+    states: &Mutex<Vec<DeviceState>>,
+    every: Duration,
+    mut on_reading: impl FnMut(&[DeviceState], DateTime<Utc>) -> Result<()>,
+) -> Result<()> {
+    // The device's own counters and info only change once in a while, no need to poll them often.
+    const DEVICE_INFO_REFRESH_PERIOD: usize = 60;
+
+    let mut ticks = interval(every);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    for iteration in 0.. {
+        ticks.tick().await;
+        let readings = join_all(
+            connections
+                .iter_mut()
+                .map(|connection| connection.current_power()),
+        )
+        .await;
+        let timestamp = Utc::now();
+
+        let refreshed = if iteration % DEVICE_INFO_REFRESH_PERIOD == 0 {
+            let infos = join_all(
+                connections
+                    .iter()
+                    .map(|connection| connection.device_info()),
+            );
+            let energy_usages = join_all(
+                connections
+                    .iter()
+                    .map(|connection| connection.energy_usage()),
+            );
+            Some(futures::join!(infos, energy_usages))
+        } else {
+            None
+        };
+
+        let mut states = states.lock().unwrap();
+        for (index, (state, connection)) in states.iter_mut().zip(&*connections).enumerate() {
+            state.timestamp = Some(timestamp);
+            state.watts = readings[index];
+            if let Some(watts) = state.watts {
+                state.energy_meter.add(&Sample { timestamp, watts });
+            }
+            state.up = connection.is_up();
+            state.errors = connection.errors();
+            // Keep what we last knew about devices that can't be reached.
+            if let Some((infos, energy_usages)) = &refreshed {
+                if let Some(info) = &infos[index] {
+                    state.info = Some(info.clone());
+                }
+                if let Some(energy_usage) = &energy_usages[index] {
+                    state.energy_usage = Some(energy_usage.clone());
+                }
+            }
+        }
+
+        on_reading(&states, timestamp)?;
+    }
+    unreachable!("polls forever")
+}
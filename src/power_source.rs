@@ -0,0 +1,132 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Days, Local, Months, NaiveDate, TimeDelta};
+#[cfg(any(feature = "metrics", feature = "daemon"))]
+use std::time::Duration;
+use tapo::{PlugEnergyMonitoringHandler, requests::EnergyDataInterval};
+
+/// Something we can read power consumption from, typically a Tapo smart plug.
+#[async_trait]
+pub trait PowerSource: Send + Sync {
+    /// Momentary power consumption in Watts.
+    async fn current_power(&self) -> Result<u64>;
+
+    /// Energy consumed today and during the current month, as counted by the device itself.
+    async fn energy_usage(&self) -> Result<EnergyUsage>;
+
+    async fn device_info(&self) -> Result<DeviceInfo>;
+
+    /// Energy consumed in Watt hours during each of the hours, days or months of the interval, as
+    /// recorded by the device. Oldest first, see [`periods`] for when each one starts.
+    async fn energy_data(&self, interval: EnergyDataInterval) -> Result<Vec<u64>>;
+
+    /// Logs in to the device again, e.g. after its session expired or it was unreachable for a
+    /// while.
+    async fn reconnect(&mut self) -> Result<()>;
+}
+
+/// A [`PowerSource`] along with how we refer to it in output.
+pub struct Device {
+    pub name: String,
+    pub source: Box<dyn PowerSource>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EnergyUsage {
+    /// Today's energy usage in Watt hours.
+    pub today_energy: u64,
+    /// Current month's energy usage in Watt hours, only reported by `serve` and `daemon`.
+    #[cfg(any(feature = "serve", feature = "daemon"))]
+    pub month_energy: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub model: String,
+    pub nickname: String,
+    /// Wi-Fi signal strength in dBm, if known. Only served by `serve --metrics` and `daemon`, like
+    /// `on_time`.
+    #[cfg(any(feature = "metrics", feature = "daemon"))]
+    pub rssi: Option<i16>,
+    /// How long the plug has been switched on, if known.
+    #[cfg(any(feature = "metrics", feature = "daemon"))]
+    pub on_time: Option<Duration>,
+}
+
+/// The start of `date` in the local time zone.
+pub fn midnight(date: NaiveDate) -> DateTime<Local> {
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|time| time.and_local_timezone(Local).earliest())
+        .expect("midnight exists")
+}
+
+/// The start and end of each entry the device returns for an energy data `interval`.
+pub fn periods(interval: &EnergyDataInterval) -> Vec<(DateTime<Local>, DateTime<Local>)> {
+    match *interval {
+        EnergyDataInterval::Hourly {
+            start_date,
+            end_date,
+        } => {
+            let start = midnight(start_date);
+            let hours = ((end_date - start_date).num_days() + 1) * 24;
+            (0..hours)
+                .map(|hour| {
+                    (
+                        start + TimeDelta::hours(hour),
+                        start + TimeDelta::hours(hour + 1),
+                    )
+                })
+                .collect()
+        }
+        EnergyDataInterval::Daily { start_date } => start_date
+            .iter_days()
+            .take_while(|date| *date < start_date + Months::new(3))
+            .map(|date| (midnight(date), midnight(date + Days::new(1))))
+            .collect(),
+        EnergyDataInterval::Monthly { start_date } => (0..12)
+            .map(|month| {
+                (
+                    midnight(start_date + Months::new(month)),
+                    midnight(start_date + Months::new(month + 1)),
+                )
+            })
+            .collect(),
+    }
+}
+
+#[async_trait]
+impl PowerSource for PlugEnergyMonitoringHandler {
+    async fn current_power(&self) -> Result<u64> {
+        Ok(self.get_current_power().await?.current_power)
+    }
+
+    async fn energy_usage(&self) -> Result<EnergyUsage> {
+        let usage = self.get_energy_usage().await?;
+        Ok(EnergyUsage {
+            today_energy: usage.today_energy,
+            #[cfg(any(feature = "serve", feature = "daemon"))]
+            month_energy: usage.month_energy,
+        })
+    }
+
+    async fn device_info(&self) -> Result<DeviceInfo> {
+        let info = self.get_device_info().await?;
+        Ok(DeviceInfo {
+            model: info.model,
+            nickname: info.nickname,
+            #[cfg(any(feature = "metrics", feature = "daemon"))]
+            rssi: Some(info.rssi),
+            #[cfg(any(feature = "metrics", feature = "daemon"))]
+            on_time: Some(Duration::from_secs(info.on_time)),
+        })
+    }
+
+    async fn energy_data(&self, interval: EnergyDataInterval) -> Result<Vec<u64>> {
+        Ok(self.get_energy_data(interval).await?.data)
+    }
+
+    async fn reconnect(&mut self) -> Result<()> {
+        self.refresh_session().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use crate::{connection::Connection, power_source::DeviceInfo};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use futures::future::try_join_all;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// A line of a recorded session. Recordings start with one `device` line per device, followed by
+/// one `sample` line per device and reading. Several recordings joined together are one session.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Entry {
+    Device {
+        name: String,
+        model: String,
+        nickname: String,
+    },
+    Sample {
+        timestamp: DateTime<Utc>,
+        device: String,
+        /// Missing while the device couldn't be reached.
+        watts: Option<u64>,
+    },
+}
+
+/// Writes what `monitor --record` reads to an NDJSON file, to be replayed later.
+pub struct Recorder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    devices: Vec<String>,
+}
+
+/// A recorded session.
+pub struct Session {
+    /// The recorded devices, by name.
+    pub devices: Vec<(String, DeviceInfo)>,
+    /// The readings of all devices, in the order of `devices`, taken at the same time.
+    pub ticks: Vec<(DateTime<Utc>, Vec<Option<u64>>)>,
+}
+
+/// How much faster than in real time to replay a session, e.g. `10x` or `0.5`.
+#[derive(Clone, Copy, Debug)]
+pub struct Speed(f64);
+
+impl Recorder {
+    /// Creates the recording, starting with what the devices say about themselves.
+    pub async fn create(path: &Path, connections: &[Connection]) -> Result<Self> {
+        let infos = try_join_all(
+            connections
+                .iter()
+                .map(|connection| connection.device.source.device_info()),
+        )
+        .await?;
+        let file =
+            File::create(path).with_context(|| format!("Creating recording {}", path.display()))?;
+        let mut recorder = Self {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            devices: connections
+                .iter()
+                .map(|connection| connection.device.name.clone())
+                .collect(),
+        };
+        for (connection, info) in connections.iter().zip(infos) {
+            recorder.write(&Entry::Device {
+                name: connection.device.name.clone(),
+                model: info.model,
+                nickname: info.nickname,
+            })?;
+        }
+        recorder.flush()?;
+        Ok(recorder)
+    }
+
+    /// Appends the readings of all devices, in the order they were given to [`Self::create`].
+    /// Flushes right away, so that the recording is complete whenever monitoring is interrupted.
+    pub fn record(&mut self, timestamp: DateTime<Utc>, readings: &[Option<u64>]) -> Result<()> {
+        let entries: Vec<Entry> = self
+            .devices
+            .iter()
+            .zip(readings)
+            .map(|(device, &watts)| Entry::Sample {
+                timestamp,
+                device: device.clone(),
+                watts,
+            })
+            .collect();
+        for entry in &entries {
+            self.write(entry)?;
+        }
+        self.flush()
+    }
+
+    fn write(&mut self, entry: &Entry) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, entry)?;
+        writeln!(self.writer).with_context(|| format!("Writing recording {}", self.path.display()))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .with_context(|| format!("Writing recording {}", self.path.display()))
+    }
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Reading recording {}", path.display()))?;
+
+        let mut session = Session {
+            devices: Vec::new(),
+            ticks: Vec::new(),
+        };
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.with_context(|| format!("Reading recording {}", path.display()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(&line).with_context(|| {
+                format!("{}:{}: not a recorded entry", path.display(), index + 1)
+            })?;
+            match entry {
+                Entry::Device {
+                    name,
+                    model,
+                    nickname,
+                } => {
+                    // Recordings joined together may name the same devices again.
+                    if session.devices.iter().any(|(known, _)| *known == name) {
+                        continue;
+                    }
+                    session.devices.push((
+                        name,
+                        DeviceInfo {
+                            model,
+                            nickname,
+                            #[cfg(any(feature = "metrics", feature = "daemon"))]
+                            rssi: None,
+                            #[cfg(any(feature = "metrics", feature = "daemon"))]
+                            on_time: None,
+                        },
+                    ));
+                    // The device wasn't recorded so far.
+                    for (_, readings) in &mut session.ticks {
+                        readings.push(None);
+                    }
+                }
+                Entry::Sample {
+                    timestamp,
+                    device,
+                    watts,
+                } => {
+                    let Some(position) =
+                        session.devices.iter().position(|(name, _)| *name == device)
+                    else {
+                        bail!(
+                            "{}:{}: {device:?} is not one of the recorded devices",
+                            path.display(),
+                            index + 1
+                        );
+                    };
+                    // Samples of all devices taken at the same time are written one after another.
+                    match session.ticks.last_mut() {
+                        Some((last, readings)) if *last == timestamp => readings[position] = watts,
+                        _ => {
+                            let mut readings = vec![None; session.devices.len()];
+                            readings[position] = watts;
+                            session.ticks.push((timestamp, readings));
+                        }
+                    }
+                }
+            }
+        }
+
+        if session.ticks.is_empty() {
+            bail!("{} contains no samples", path.display());
+        }
+        Ok(session)
+    }
+}
+
+impl Speed {
+    /// How long to wait in between replaying readings that were `elapsed` apart.
+    pub fn scale(&self, elapsed: Duration) -> Duration {
+        elapsed.div_f64(self.0)
+    }
+}
+
+impl FromStr for Speed {
+    type Err = anyhow::Error;
+
+    fn from_str(speed: &str) -> Result<Self> {
+        let factor: f64 = speed
+            .strip_suffix('x')
+            .unwrap_or(speed)
+            .parse()
+            .with_context(|| format!("Invalid speed {speed:?}, expected e.g. 10x"))?;
+        if !factor.is_finite() || factor <= 0.0 {
+            bail!("The speed must be positive, not {speed:?}");
+        }
+        Ok(Self(factor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, contents: &str) -> Result<Session> {
+        let path = std::env::temp_dir().join(format!("{name}-{}.ndjson", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let session = Session::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        session
+    }
+
+    fn device(name: &str) -> String {
+        format!(r#"{{"type":"device","name":"{name}","model":"P115","nickname":"{name}"}}"#)
+    }
+
+    fn sample(second: u32, device: &str, watts: Option<u64>) -> String {
+        let watts = watts.map_or("null".to_string(), |watts| watts.to_string());
+        format!(
+            r#"{{"type":"sample","timestamp":"2025-06-01T12:00:{second:02}Z","device":"{device}","watts":{watts}}}"#
+        )
+    }
+
+    fn readings(session: &Session) -> Vec<Vec<Option<u64>>> {
+        session
+            .ticks
+            .iter()
+            .map(|(_, readings)| readings.clone())
+            .collect()
+    }
+
+    #[test]
+    fn loads_readings_taken_at_the_same_time_together() {
+        let session = load(
+            "together",
+            &[
+                device("desk"),
+                device("fridge"),
+                sample(0, "desk", Some(10)),
+                sample(0, "fridge", Some(80)),
+                sample(1, "desk", None),
+                sample(1, "fridge", Some(81)),
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        let names: Vec<&str> = session
+            .devices
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["desk", "fridge"]);
+        assert_eq!(
+            readings(&session),
+            [vec![Some(10), Some(80)], vec![None, Some(81)]]
+        );
+    }
+
+    #[test]
+    fn loads_concatenated_recordings() {
+        let first = [device("desk"), sample(0, "desk", Some(10))];
+        let second = [
+            device("fridge"),
+            device("desk"),
+            sample(5, "fridge", Some(80)),
+            sample(5, "desk", Some(12)),
+        ];
+        // As joined by `cat`, each recording ending in a newline.
+        let recording = |lines: &[String]| lines.join("\n") + "\n";
+        let session = load("concatenated", &(recording(&first) + &recording(&second))).unwrap();
+
+        let names: Vec<&str> = session
+            .devices
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["desk", "fridge"]);
+        // The fridge wasn't recorded at first.
+        assert_eq!(
+            readings(&session),
+            [vec![Some(10), None], vec![Some(12), Some(80)]]
+        );
+    }
+
+    #[test]
+    fn rejects_samples_of_unknown_devices() {
+        let error = load(
+            "unknown",
+            &[device("desk"), sample(0, "fridge", Some(80))].join("\n"),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(
+            error.ends_with(":2: \"fridge\" is not one of the recorded devices"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn rejects_recordings_without_samples() {
+        assert!(load("empty", &device("desk")).is_err());
+    }
+}
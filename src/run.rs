@@ -0,0 +1,166 @@
+use crate::{
+    energy::{integrate, joules_to_watt_hours},
+    measure::{Sample, StopCondition, get_samples},
+    power_source::PowerSource,
+    tariff::Tariff,
+};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use std::{process::ExitStatus, time::Duration};
+use tokio::{
+    process::Command,
+    signal::ctrl_c,
+    time::{MissedTickBehavior, interval},
+};
+
+/// What running a command cost in terms of energy.
+struct Report {
+    duration: Duration,
+    average_watts: f64,
+    baseline_watts: Option<f64>,
+    peak_watts: u64,
+    /// In Joules.
+    energy: f64,
+    cost: Option<f64>,
+    tariff: Option<Tariff>,
+}
+
+/// Runs `command` to completion while sampling power, like `time` does for CPU time. Returns the
+/// exit status of the command.
+pub async fn run(
+    device: &dyn PowerSource,
+    command: &[String],
+    baseline_samples: usize,
+    sampling_interval: Duration,
+    tariff: Option<Tariff>,
+) -> Result<ExitStatus> {
+    let baseline_watts = if baseline_samples > 0 {
+        eprintln!("measuring idle baseline...");
+        let stop = StopCondition::AfterSamples(baseline_samples);
+        let samples = get_samples(device, stop, sampling_interval).await?;
+        Some(
+            samples
+                .iter()
+                .map(|sample| sample.watts as f64)
+                .sum::<f64>()
+                / samples.len() as f64,
+        )
+    } else {
+        None
+    };
+
+    let (program, arguments) = command.split_first().expect("clap requires a command");
+    let mut child = Command::new(program)
+        .args(arguments)
+        .spawn()
+        .with_context(|| format!("Running {program}"))?;
+    let started = Utc::now();
+
+    // Always try for a reading, even for very short commands.
+    let mut samples = Vec::new();
+    read(device, &mut samples).await;
+
+    let mut ticks = interval(sampling_interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately, but we just took a sample.
+    ticks.tick().await;
+
+    let status = loop {
+        tokio::select! {
+            _ = ticks.tick() => read(device, &mut samples).await,
+            status = child.wait() => break status?,
+            // The command gets the interrupt too. Report on whatever it ran for once it exits.
+            _ = ctrl_c() => {},
+        }
+    };
+    let finished = Utc::now();
+
+    let (Some(first), Some(last)) = (samples.first().cloned(), samples.last().cloned()) else {
+        eprintln!("warning: no reading succeeded, can't tell the energy the command consumed");
+        return Ok(status);
+    };
+    // Stretch the first and last readings over the whole lifetime of the command.
+    samples.insert(
+        0,
+        Sample {
+            timestamp: started,
+            ..first
+        },
+    );
+    samples.push(Sample {
+        timestamp: finished,
+        ..last
+    });
+
+    let energy = integrate(&samples, tariff.clone());
+    let duration = (finished - started).to_std().unwrap_or_default();
+    let average_watts = if duration.is_zero() {
+        // Too short to integrate over, the readings are all we have.
+        samples
+            .iter()
+            .map(|sample| sample.watts as f64)
+            .sum::<f64>()
+            / samples.len() as f64
+    } else {
+        energy.joules() / duration.as_secs_f64()
+    };
+    let report = Report {
+        duration,
+        average_watts,
+        baseline_watts,
+        peak_watts: samples
+            .iter()
+            .map(|sample| sample.watts)
+            .max()
+            .unwrap_or_default(),
+        energy: energy.joules(),
+        cost: energy.cost(),
+        tariff,
+    };
+    report.print(&command.join(" "));
+
+    Ok(status)
+}
+
+/// Adds a reading to `samples`. A failed one is left as a gap, bridged by the readings around it,
+/// rather than abandoning the command while it still runs.
+async fn read(device: &dyn PowerSource, samples: &mut Vec<Sample>) {
+    match device.current_power().await {
+        Ok(watts) => samples.push(Sample {
+            watts,
+            timestamp: Utc::now(),
+        }),
+        Err(error) => eprintln!("warning: reading the power: {error:#}"),
+    }
+}
+
+impl Report {
+    /// Prints to stderr, the command's own output goes to stdout.
+    fn print(&self, command: &str) {
+        let seconds = self.duration.as_secs_f64();
+        let energy_wh = joules_to_watt_hours(self.energy);
+
+        eprintln!();
+        eprintln!("command: {command}");
+        eprintln!("duration: {seconds:.2} s");
+        match self.baseline_watts {
+            Some(baseline) => eprintln!(
+                "avg: {:.1} W (idle baseline {baseline:.1} W)",
+                self.average_watts
+            ),
+            None => eprintln!("avg: {:.1} W", self.average_watts),
+        }
+        eprintln!("peak: {} W", self.peak_watts);
+        eprintln!("energy: {energy_wh:.4} Wh ({:.1} J)", self.energy);
+        if let Some(baseline) = self.baseline_watts {
+            let above_baseline = self.energy - baseline * seconds;
+            eprintln!(
+                "energy above baseline: {:.4} Wh ({above_baseline:.1} J)",
+                joules_to_watt_hours(above_baseline)
+            );
+        }
+        if let (Some(cost), Some(tariff)) = (self.cost, &self.tariff) {
+            eprintln!("cost: {}", tariff.format(cost));
+        }
+    }
+}
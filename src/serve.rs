@@ -0,0 +1,87 @@
+#[cfg(feature = "influx")]
+use crate::influx::InfluxArgs;
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsArgs;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttArgs;
+use crate::{
+    connection::{Connection, print_outages},
+    polling::{DeviceState, poll},
+    power_source::Device,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use clap::ArgGroup;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{select, signal::ctrl_c};
+
+/// Where `serve` sends the readings, at least one of them.
+#[derive(clap::Args, Clone, Debug)]
+#[command(group(ArgGroup::new("outputs").required(true).multiple(true)))]
+pub struct ServeArgs {
+    #[cfg(feature = "metrics")]
+    #[command(flatten)]
+    metrics: MetricsArgs,
+    #[cfg(feature = "mqtt")]
+    #[command(flatten)]
+    mqtt: MqttArgs,
+    #[cfg(feature = "influx")]
+    #[command(flatten)]
+    influx: InfluxArgs,
+}
+
+/// Somewhere `serve` sends the readings to.
+#[async_trait]
+pub trait Output: Send {
+    /// Takes the latest states of all devices, after every reading.
+    fn publish(&mut self, devices: &[DeviceState], timestamp: DateTime<Utc>) -> Result<()>;
+
+    /// Finishes up once `serve` is interrupted.
+    async fn close(self: Box<Self>) {}
+}
+
+impl ServeArgs {
+    /// Starts sending the readings wherever asked to.
+    async fn outputs(
+        &self,
+        devices: &Arc<Mutex<Vec<DeviceState>>>,
+    ) -> Result<Vec<Box<dyn Output>>> {
+        let mut outputs = Vec::new();
+        #[cfg(feature = "metrics")]
+        outputs.extend(self.metrics.output(devices).await?);
+        #[cfg(feature = "mqtt")]
+        outputs.extend(self.mqtt.output(devices)?);
+        #[cfg(feature = "influx")]
+        outputs.extend(self.influx.output(devices)?);
+        Ok(outputs)
+    }
+}
+
+/// Polls the devices until interrupted, serving their latest readings to Prometheus at `/metrics`,
+/// publishing them over MQTT and writing them to InfluxDB, as asked to. Devices that stop
+/// answering are reconnected to.
+pub async fn serve(devices: Vec<Device>, args: &ServeArgs, every: Duration) -> Result<()> {
+    let mut connections: Vec<Connection> = devices.into_iter().map(Connection::new).collect();
+    let states = Arc::new(Mutex::new(DeviceState::all(&connections)));
+    let mut outputs = args.outputs(&states).await?;
+
+    let publish = |states: &[DeviceState], timestamp| {
+        outputs
+            .iter_mut()
+            .try_for_each(|output| output.publish(states, timestamp))
+    };
+    let result = select! {
+        result = poll(&mut connections, &states, every, publish) => result,
+        _ = ctrl_c() => Ok(()),
+    };
+    for output in outputs {
+        output.close().await;
+    }
+
+    print_outages(&connections);
+    result
+}
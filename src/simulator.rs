@@ -0,0 +1,383 @@
+use crate::power_source::{DeviceInfo, EnergyUsage, PowerSource, periods};
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use chrono::Local;
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tapo::requests::EnergyDataInterval;
+
+/// How the wattage of a [`SimulatedPlug`] evolves over time.
+#[derive(Clone, Debug)]
+pub enum Profile {
+    /// Always the same reading, e.g. `constant:60`.
+    Constant { watts: u64 },
+    /// Alternates between two readings, e.g. `square:5:120:10s` spends 5 seconds at each level.
+    Square {
+        low: u64,
+        high: u64,
+        period: Duration,
+    },
+    /// A mostly idle load with some random jitter around it, e.g. `noisy-idle:40:3`.
+    NoisyIdle { watts: u64, noise: u64 },
+    /// Climbs linearly and stays at the final reading, e.g. `ramp:10:200:1m`.
+    Ramp {
+        from: u64,
+        to: u64,
+        duration: Duration,
+    },
+    /// Loops over a CSV file with one reading per second in its last column, e.g. `replay:trace.csv`.
+    Replay { readings: Vec<u64> },
+}
+
+impl Profile {
+    const SYNTAX: &str = "constant:<W>, square:<LOW W>:<HIGH W>:<PERIOD>, \
+        noisy-idle:<W>[:<NOISE W>], ramp:<FROM W>:<TO W>:<DURATION> or replay:<CSV FILE>";
+
+    fn name(&self) -> &'static str {
+        match self {
+            Profile::Constant { .. } => "constant",
+            Profile::Square { .. } => "square",
+            Profile::NoisyIdle { .. } => "noisy-idle",
+            Profile::Ramp { .. } => "ramp",
+            Profile::Replay { .. } => "replay",
+        }
+    }
+
+    fn watts_at(&self, elapsed: Duration) -> u64 {
+        match *self {
+            Profile::Constant { watts } => watts,
+            Profile::Square { low, high, period } => {
+                let phase = elapsed.as_secs_f64() % period.as_secs_f64();
+                if phase < period.as_secs_f64() / 2.0 {
+                    low
+                } else {
+                    high
+                }
+            }
+            Profile::NoisyIdle { watts, noise } => {
+                let jitter = rand::random_range(-(noise as i64)..=noise as i64);
+                watts.saturating_add_signed(jitter)
+            }
+            Profile::Ramp { from, to, duration } => {
+                let progress = (elapsed.as_secs_f64() / duration.as_secs_f64()).min(1.0);
+                (from as f64 + (to as f64 - from as f64) * progress).round() as u64
+            }
+            Profile::Replay { ref readings } => {
+                readings[elapsed.as_secs() as usize % readings.len()]
+            }
+        }
+    }
+
+    /// Mean power over the first hour, to make up a plausible energy history.
+    fn typical_watts(&self) -> f64 {
+        const HOUR: u64 = 3600;
+        (0..HOUR)
+            .map(|second| self.watts_at(Duration::from_secs(second)) as f64)
+            .sum::<f64>()
+            / HOUR as f64
+    }
+
+    fn load_replay(path: PathBuf) -> Result<Vec<u64>> {
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Reading replay file {}", path.display()))?;
+
+        let mut readings = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let Some(column) = line.rsplit(',').next().map(str::trim) else {
+                continue;
+            };
+            match column.parse::<f64>() {
+                Ok(watts) => readings.push(watts.round() as u64),
+                // Tolerate a header row and blank lines.
+                Err(_) if index == 0 || column.is_empty() => continue,
+                Err(_) => bail!(
+                    "{}:{}: {column:?} is not a reading",
+                    path.display(),
+                    index + 1
+                ),
+            }
+        }
+
+        if readings.is_empty() {
+            bail!("{} contains no readings", path.display());
+        }
+        Ok(readings)
+    }
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (kind, params) = spec.split_once(':').unwrap_or((spec, ""));
+        let params: Vec<&str> = params.split(':').collect();
+        let watts = |index: usize| -> Result<u64> {
+            let param = params.get(index).context("Missing a profile parameter")?;
+            param
+                .parse()
+                .with_context(|| format!("{param:?} is not a number of Watts"))
+        };
+        let duration = |index: usize| -> Result<Duration> {
+            let param = params.get(index).context("Missing a profile parameter")?;
+            let duration = humantime::parse_duration(param)?;
+            if duration.is_zero() {
+                bail!("duration must not be zero");
+            }
+            Ok(duration)
+        };
+
+        let profile = match kind {
+            "constant" => Profile::Constant { watts: watts(0)? },
+            "square" => Profile::Square {
+                low: watts(0)?,
+                high: watts(1)?,
+                period: duration(2)?,
+            },
+            "noisy-idle" => Profile::NoisyIdle {
+                watts: watts(0)?,
+                noise: if params.len() > 1 { watts(1)? } else { 2 },
+            },
+            "ramp" => Profile::Ramp {
+                from: watts(0)?,
+                to: watts(1)?,
+                duration: duration(2)?,
+            },
+            "replay" => Profile::Replay {
+                readings: Self::load_replay(params.join(":").into())?,
+            },
+            _ => bail!("Unknown profile {kind:?}, expected one of {}", Self::SYNTAX),
+        };
+
+        Ok(profile)
+    }
+}
+
+/// A fake plug producing synthetic readings according to a [`Profile`], so that the tool can be
+/// used and developed without a device on the network.
+pub struct SimulatedPlug {
+    profile: Profile,
+    started: Instant,
+    state: Mutex<SimulationState>,
+}
+
+struct SimulationState {
+    last_reading: u64,
+    last_reading_at: Instant,
+    /// Energy consumed so far in Watt seconds.
+    energy: f64,
+}
+
+impl SimulatedPlug {
+    pub fn new(profile: Profile) -> Self {
+        let started = Instant::now();
+        let last_reading = profile.watts_at(Duration::ZERO);
+        Self {
+            profile,
+            started,
+            state: Mutex::new(SimulationState {
+                last_reading,
+                last_reading_at: started,
+                energy: 0.0,
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl PowerSource for SimulatedPlug {
+    async fn current_power(&self) -> Result<u64> {
+        let now = Instant::now();
+        let reading = self.profile.watts_at(now - self.started);
+
+        let mut state = self.state.lock().unwrap();
+        state.energy += state.last_reading as f64 * (now - state.last_reading_at).as_secs_f64();
+        state.last_reading = reading;
+        state.last_reading_at = now;
+
+        Ok(reading)
+    }
+
+    async fn energy_usage(&self) -> Result<EnergyUsage> {
+        let energy = (self.state.lock().unwrap().energy / 3600.0) as u64;
+        Ok(EnergyUsage {
+            today_energy: energy,
+            #[cfg(any(feature = "serve", feature = "daemon"))]
+            month_energy: energy,
+        })
+    }
+
+    async fn device_info(&self) -> Result<DeviceInfo> {
+        Ok(DeviceInfo {
+            model: "Simulated".to_string(),
+            nickname: format!("{} profile", self.profile.name()),
+            #[cfg(any(feature = "metrics", feature = "daemon"))]
+            rssi: None,
+            #[cfg(any(feature = "metrics", feature = "daemon"))]
+            on_time: Some(self.started.elapsed()),
+        })
+    }
+
+    /// Pretends the profile has always been running, up until now.
+    async fn energy_data(&self, interval: EnergyDataInterval) -> Result<Vec<u64>> {
+        let now = Local::now();
+        let watts = self.profile.typical_watts();
+        Ok(periods(&interval)
+            .into_iter()
+            .map(|(start, end)| {
+                let hours = (end.min(now) - start).as_seconds_f64().max(0.0) / 3600.0;
+                (watts * hours) as u64
+            })
+            .collect())
+    }
+
+    /// There is no session to lose.
+    async fn reconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(spec: &str) -> Profile {
+        spec.parse().unwrap()
+    }
+
+    fn secs(seconds: f64) -> Duration {
+        Duration::from_secs_f64(seconds)
+    }
+
+    #[test]
+    fn parses_profiles() {
+        assert!(matches!(
+            profile("constant:60"),
+            Profile::Constant { watts: 60 }
+        ));
+        assert!(matches!(
+            profile("square:5:120:10s"),
+            Profile::Square { low: 5, high: 120, period } if period == secs(10.0)
+        ));
+        assert!(matches!(
+            profile("noisy-idle:40:3"),
+            Profile::NoisyIdle {
+                watts: 40,
+                noise: 3
+            }
+        ));
+        assert!(matches!(
+            profile("noisy-idle:40"),
+            Profile::NoisyIdle {
+                watts: 40,
+                noise: 2
+            }
+        ));
+        assert!(matches!(
+            profile("ramp:10:200:1m"),
+            Profile::Ramp { from: 10, to: 200, duration } if duration == secs(60.0)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_profiles() {
+        for spec in [
+            "",
+            "constant",
+            "constant:",
+            "constant:-5",
+            "constant:lots",
+            "square:5:120",
+            "square:5:120:0s",
+            "ramp:10:200:soon",
+            "sine:50",
+            "replay:does/not/exist.csv",
+        ] {
+            assert!(spec.parse::<Profile>().is_err(), "{spec:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn constant() {
+        let profile = profile("constant:60");
+        for elapsed in [0.0, 0.5, 3600.0] {
+            assert_eq!(profile.watts_at(secs(elapsed)), 60);
+        }
+        assert_eq!(profile.typical_watts(), 60.0);
+    }
+
+    #[test]
+    fn square() {
+        let profile = profile("square:5:120:10s");
+        assert_eq!(profile.watts_at(secs(0.0)), 5);
+        assert_eq!(profile.watts_at(secs(4.9)), 5);
+        assert_eq!(profile.watts_at(secs(5.0)), 120);
+        assert_eq!(profile.watts_at(secs(9.9)), 120);
+        // The next period.
+        assert_eq!(profile.watts_at(secs(10.0)), 5);
+        assert_eq!(profile.watts_at(secs(15.0)), 120);
+        assert_eq!(profile.typical_watts(), 62.5);
+    }
+
+    #[test]
+    fn noisy_idle_stays_around_the_idle_reading() {
+        let profile = profile("noisy-idle:40:3");
+        for second in 0..1000 {
+            assert!((37..=43).contains(&profile.watts_at(secs(second as f64))));
+        }
+    }
+
+    #[test]
+    fn noisy_idle_does_not_go_below_zero() {
+        let profile = profile("noisy-idle:1:5");
+        for second in 0..1000 {
+            assert!(profile.watts_at(secs(second as f64)) <= 6);
+        }
+    }
+
+    #[test]
+    fn ramp() {
+        let profile = profile("ramp:10:200:1m");
+        assert_eq!(profile.watts_at(secs(0.0)), 10);
+        assert_eq!(profile.watts_at(secs(30.0)), 105);
+        assert_eq!(profile.watts_at(secs(60.0)), 200);
+        // Stays at the final reading.
+        assert_eq!(profile.watts_at(secs(600.0)), 200);
+
+        let down = self::profile("ramp:200:10:1m");
+        assert_eq!(down.watts_at(secs(30.0)), 105);
+        assert_eq!(down.watts_at(secs(90.0)), 10);
+    }
+
+    #[test]
+    fn replay_loops_over_the_last_column() {
+        let path = std::env::temp_dir().join(format!("replay-{}.csv", std::process::id()));
+        std::fs::write(&path, "timestamp,watts\n0,10\n1,20.4\n\n2,29.6\n").unwrap();
+        let profile = profile(&format!("replay:{}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+
+        let readings: Vec<u64> = (0..7)
+            .map(|second| profile.watts_at(secs(second as f64)))
+            .collect();
+        assert_eq!(readings, [10, 20, 30, 10, 20, 30, 10]);
+        // Within a second, the reading holds.
+        assert_eq!(profile.watts_at(secs(1.9)), 20);
+    }
+
+    #[test]
+    fn replay_rejects_files_without_readings() {
+        let path = std::env::temp_dir().join(format!("replay-empty-{}.csv", std::process::id()));
+        std::fs::write(&path, "timestamp,watts\n").unwrap();
+        let empty = format!("replay:{}", path.display()).parse::<Profile>();
+        std::fs::write(&path, "timestamp,watts\n0,10\n1,lots\n").unwrap();
+        let invalid = format!("replay:{}", path.display()).parse::<Profile>();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(empty.is_err());
+        let error = invalid.unwrap_err().to_string();
+        assert!(error.ends_with(":3: \"lots\" is not a reading"), "{error}");
+    }
+}
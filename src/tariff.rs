@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeZone, Weekday};
+use serde::Deserialize;
+use std::path::Path;
+
+/// Electricity prices, either flat or depending on the time of use. Loaded from a TOML file like
+///
+/// ```toml
+/// currency = "EUR"
+/// # Outside of any of the periods below.
+/// price_per_kwh = 0.25
+///
+/// [[periods]]
+/// name = "peak"
+/// days = "weekdays"
+/// start = "07:00"
+/// end = "22:00"
+/// price_per_kwh = 0.40
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tariff {
+    #[serde(default)]
+    pub currency: String,
+    pub price_per_kwh: f64,
+    /// The first matching period determines the price.
+    #[serde(default)]
+    pub periods: Vec<Period>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Period {
+    pub name: String,
+    #[serde(default)]
+    pub days: Days,
+    /// Periods with `end` before `start` extend past midnight.
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub price_per_kwh: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Days {
+    #[default]
+    All,
+    Weekdays,
+    Weekends,
+}
+
+impl Days {
+    fn contains(&self, weekday: Weekday) -> bool {
+        let weekend = matches!(weekday, Weekday::Sat | Weekday::Sun);
+        match self {
+            Days::All => true,
+            Days::Weekdays => !weekend,
+            Days::Weekends => weekend,
+        }
+    }
+}
+
+impl Period {
+    fn contains(&self, time: DateTime<Local>) -> bool {
+        let time_of_day = time.time();
+        if self.start < self.end {
+            self.days.contains(time.weekday()) && (self.start..self.end).contains(&time_of_day)
+        } else if time_of_day >= self.start {
+            self.days.contains(time.weekday())
+        } else {
+            // In the part past midnight, which belongs to the period starting the day before.
+            time_of_day < self.end && self.days.contains(time.weekday().pred())
+        }
+    }
+}
+
+impl Tariff {
+    pub fn flat(price_per_kwh: f64, currency: String) -> Self {
+        Self {
+            currency,
+            price_per_kwh,
+            periods: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading tariff from {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Parsing tariff {}", path.display()))
+    }
+
+    pub fn is_time_of_use(&self) -> bool {
+        !self.periods.is_empty()
+    }
+
+    /// The period in effect at `time`, if any.
+    pub fn period_at<Tz: TimeZone>(&self, time: DateTime<Tz>) -> Option<&Period> {
+        let time = time.with_timezone(&Local);
+        self.periods.iter().find(|period| period.contains(time))
+    }
+
+    /// Price of consuming `watt_hours` starting at `time`.
+    pub fn cost<Tz: TimeZone>(&self, time: DateTime<Tz>, watt_hours: f64) -> f64 {
+        let price_per_kwh = self
+            .period_at(time)
+            .map_or(self.price_per_kwh, |period| period.price_per_kwh);
+        price_per_kwh * watt_hours / 1000.0
+    }
+
+    pub fn format(&self, amount: f64) -> String {
+        format_cost(amount, &self.currency)
+    }
+}
+
+pub fn format_cost(amount: f64, currency: &str) -> String {
+    // Short measurements cost fractions of a cent.
+    let precision = if amount.abs() < 1.0 { 4 } else { 2 };
+    format!("{amount:.precision$} {currency}")
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Weekday peak hours and a weekday night rate past midnight, cheaper otherwise.
+    fn tariff() -> Tariff {
+        toml::from_str(
+            r#"
+            currency = "EUR"
+            price_per_kwh = 0.25
+
+            [[periods]]
+            name = "peak"
+            days = "weekdays"
+            start = "07:00"
+            end = "22:00"
+            price_per_kwh = 0.40
+
+            [[periods]]
+            name = "night"
+            days = "weekdays"
+            start = "22:00"
+            end = "06:00"
+            price_per_kwh = 0.10
+            "#,
+        )
+        .unwrap()
+    }
+
+    /// The period in effect on the given day of June 2025, which starts on a Sunday.
+    fn period_at(day: u32, hour: u32, minute: u32) -> Option<String> {
+        let time = Local
+            .with_ymd_and_hms(2025, 6, day, hour, minute, 0)
+            .unwrap();
+        tariff().period_at(time).map(|period| period.name.clone())
+    }
+
+    #[test]
+    fn periods_within_a_day() {
+        // Monday.
+        assert_eq!(period_at(2, 6, 59), None);
+        assert_eq!(period_at(2, 7, 0).as_deref(), Some("peak"));
+        assert_eq!(period_at(2, 21, 59).as_deref(), Some("peak"));
+        // Saturday.
+        assert_eq!(period_at(7, 12, 0), None);
+    }
+
+    #[test]
+    fn period_across_midnight() {
+        // Monday night into Tuesday.
+        assert_eq!(period_at(2, 22, 0).as_deref(), Some("night"));
+        assert_eq!(period_at(2, 23, 59).as_deref(), Some("night"));
+        assert_eq!(period_at(3, 0, 0).as_deref(), Some("night"));
+        assert_eq!(period_at(3, 5, 59).as_deref(), Some("night"));
+        assert_eq!(period_at(3, 6, 0), None);
+    }
+
+    #[test]
+    fn period_across_midnight_at_the_weekday_boundary() {
+        // Friday night carries on into Saturday morning.
+        assert_eq!(period_at(6, 23, 0).as_deref(), Some("night"));
+        assert_eq!(period_at(7, 1, 0).as_deref(), Some("night"));
+        // But Saturday night doesn't start, and neither does Sunday night into Monday.
+        assert_eq!(period_at(7, 23, 0), None);
+        assert_eq!(period_at(8, 1, 0), None);
+        assert_eq!(period_at(8, 23, 0), None);
+        assert_eq!(period_at(9, 1, 0), None);
+    }
+
+    #[test]
+    fn costs_at_the_price_in_effect() {
+        let tariff = tariff();
+        let at = |day, hour| Local.with_ymd_and_hms(2025, 6, day, hour, 0, 0).unwrap();
+        assert_eq!(tariff.cost(at(2, 12), 1000.0), 0.40);
+        assert_eq!(tariff.cost(at(3, 2), 1000.0), 0.10);
+        // Outside of any period.
+        assert_eq!(tariff.cost(at(7, 12), 1000.0), 0.25);
+        assert_eq!(
+            Tariff::flat(0.30, String::new()).cost(at(2, 12), 500.0),
+            0.15
+        );
+    }
+
+    #[test]
+    fn formats_costs() {
+        assert_eq!(format_cost(0.00123, "EUR"), "0.0012 EUR");
+        assert_eq!(format_cost(12.345, "EUR"), "12.35 EUR");
+        assert_eq!(format_cost(12.345, ""), "12.35");
+    }
+}
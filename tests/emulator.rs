@@ -0,0 +1,893 @@
+//! End to end tests running `tapo-power-monitor` against `tapo-emulator`.
+#![cfg(feature = "emulator")]
+
+#[cfg(any(
+    feature = "metrics",
+    feature = "mqtt",
+    feature = "influx",
+    feature = "daemon"
+))]
+use std::io::{Read, Write};
+#[cfg(any(feature = "metrics", feature = "daemon"))]
+use std::net::TcpStream;
+use std::{
+    io::{BufRead, BufReader},
+    net::TcpListener,
+    process::{Child, Command, Output, Stdio},
+};
+
+const USERNAME: &str = "tester@example.com";
+const PASSWORD: &str = "correct horse battery staple";
+
+struct Emulator {
+    process: Child,
+    port: u16,
+    /// Where the emulator answers discovery requests, if asked to.
+    discovery_port: Option<u16>,
+}
+
+impl Emulator {
+    fn start(extra_args: &[&str]) -> Self {
+        Self::start_on("127.0.0.1:0", extra_args)
+    }
+
+    fn start_on(listen: &str, extra_args: &[&str]) -> Self {
+        let mut process = Command::new(env!("CARGO_BIN_EXE_tapo-emulator"))
+            .args([
+                "--listen",
+                listen,
+                "--username",
+                USERNAME,
+                "--password",
+                PASSWORD,
+            ])
+            .args(extra_args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("emulator starts");
+
+        let mut stdout = BufReader::new(process.stdout.take().unwrap());
+        let port = read_port(&mut stdout);
+        let discovery_port = extra_args
+            .contains(&"--discovery")
+            .then(|| read_port(&mut stdout));
+
+        Self {
+            process,
+            port,
+            discovery_port,
+        }
+    }
+
+    fn run(&self, password: &str, args: &[&str]) -> Output {
+        run(self.port, password, args)
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Just enough of an MQTT broker to receive what is published to it at QoS 0.
+#[cfg(feature = "mqtt")]
+struct MqttBroker {
+    port: u16,
+    messages: std::sync::mpsc::Receiver<MqttMessage>,
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Debug)]
+struct MqttMessage {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+#[cfg(feature = "mqtt")]
+impl MqttBroker {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, messages) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0; 1];
+            while stream.read_exact(&mut header).is_ok() {
+                // The remaining length is a variable length integer.
+                let (mut length, mut shift, mut byte) = (0, 0, [0x80]);
+                while byte[0] & 0x80 != 0 {
+                    stream.read_exact(&mut byte).unwrap();
+                    length |= ((byte[0] & 0x7f) as usize) << shift;
+                    shift += 7;
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).unwrap();
+
+                match header[0] >> 4 {
+                    // CONNECT, answered by CONNACK.
+                    1 => stream.write_all(&[0x20, 2, 0, 0]).unwrap(),
+                    // PUBLISH
+                    3 => {
+                        let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8_lossy(&body[2..2 + topic_length]);
+                        let payload = String::from_utf8_lossy(&body[2 + topic_length..]);
+                        let _ = sender.send(MqttMessage {
+                            topic: topic.into_owned(),
+                            payload: payload.into_owned(),
+                            retain: header[0] & 1 != 0,
+                        });
+                    }
+                    // PINGREQ, answered by PINGRESP.
+                    12 => stream.write_all(&[0xd0, 0]).unwrap(),
+                    _ => {}
+                }
+            }
+        });
+
+        Self { port, messages }
+    }
+}
+
+/// Reads the port from an `... on IP:PORT` line the emulator prints on startup.
+fn read_port(stdout: &mut impl BufRead) -> u16 {
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    line.trim()
+        .rsplit(':')
+        .next()
+        .unwrap()
+        .parse()
+        .expect("emulator prints its port")
+}
+
+/// The tool with credentials set, and without picking up the config of whoever runs the tests.
+fn tapo_power_monitor(password: &str) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_tapo-power-monitor"));
+    command
+        .env("TAPO_USERNAME", USERNAME)
+        .env("TAPO_PASSWORD", password)
+        .env("XDG_CONFIG_HOME", env!("CARGO_TARGET_TMPDIR"));
+    command
+}
+
+fn run(port: u16, password: &str, args: &[&str]) -> Output {
+    tapo_power_monitor(password)
+        .args(["127.0.0.1", "--port", &port.to_string()])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// The body of a plain HTTP/1.0 GET request.
+#[cfg(any(feature = "metrics", feature = "daemon"))]
+fn http_get(address: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "GET {path} HTTP/1.0\r\nHost: {address}\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_string())
+        .unwrap_or_default()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn measure_over_klap() {
+    let emulator = Emulator::start(&["--watts", "77"]);
+
+    let output = emulator.run(PASSWORD, &["measure"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("avg: 77.0 W +-0.0 W"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn measure_over_passthrough() {
+    let emulator = Emulator::start(&["--protocol", "passthrough"]);
+
+    let output = emulator.run(PASSWORD, &["measure"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("avg: 42.0 W"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn cost_from_energy_history() {
+    let emulator = Emulator::start(&["--watts", "100"]);
+
+    let output = emulator.run(
+        PASSWORD,
+        &["--price-per-kwh", "0.5", "--currency", "EUR", "cost"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("this month:"),
+        "{}",
+        stdout(&output)
+    );
+    assert!(stdout(&output).contains(" EUR"), "{}", stdout(&output));
+}
+
+#[test]
+fn monitor_several_devices() {
+    let desk = Emulator::start(&["--watts", "30"]);
+    let screen = Emulator::start(&["--watts", "70", "--protocol", "passthrough"]);
+
+    let mut monitor = tapo_power_monitor(PASSWORD)
+        .args(["--device", &format!("desk=127.0.0.1:{}", desk.port)])
+        .args(["--device", &format!("screen=127.0.0.1:{}", screen.port)])
+        .args(["monitor", "--output", "ndjson", "--total"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let records: Vec<String> = BufReader::new(monitor.stdout.take().unwrap())
+        .lines()
+        .take(3)
+        .map(Result::unwrap)
+        .collect();
+    let _ = monitor.kill();
+    let _ = monitor.wait();
+
+    assert_eq!(records.len(), 3);
+    assert!(
+        records[0].contains(r#""device":"desk","watts":30"#),
+        "{records:?}"
+    );
+    assert!(
+        records[1].contains(r#""device":"screen","watts":70"#),
+        "{records:?}"
+    );
+    assert!(
+        records[2].contains(r#""device":"total","watts":100"#),
+        "{records:?}"
+    );
+}
+
+#[test]
+fn monitor_reconnects() {
+    let emulator = Emulator::start(&["--session-lifetime", "1s"]);
+    let port = emulator.port;
+
+    let mut monitor = tapo_power_monitor(PASSWORD)
+        .args(["127.0.0.1", "--port", &port.to_string()])
+        .args(["monitor", "--output", "ndjson"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut records = BufReader::new(monitor.stdout.take().unwrap())
+        .lines()
+        .map(Result::unwrap);
+    let mut next_record = |what: &str| {
+        records
+            .next()
+            .unwrap_or_else(|| panic!("monitor exited waiting for {what}"))
+    };
+
+    // Outliving the session doesn't interrupt the readings.
+    for _ in 0..3 {
+        let record = next_record("readings");
+        assert!(record.contains(r#""watts":42"#), "{record}");
+    }
+
+    drop(emulator);
+    while !next_record("a gap").contains(r#""watts":null"#) {}
+    let _emulator = Emulator::start_on(&format!("127.0.0.1:{port}"), &[]);
+    while !next_record("readings to resume").contains(r#""watts":42"#) {}
+
+    Command::new("kill")
+        .args(["-INT", &monitor.id().to_string()])
+        .status()
+        .unwrap();
+    let output = monitor.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("outages:\n  127.0.0.1: "), "{stderr}");
+    assert!(stderr.contains("Connection refused"), "{stderr}");
+}
+
+#[test]
+fn run_outlives_the_device() {
+    let emulator = Emulator::start(&[]);
+    let run = tapo_power_monitor(PASSWORD)
+        .args(["127.0.0.1", "--port", &emulator.port.to_string()])
+        .args([
+            "run",
+            "--baseline-samples",
+            "0",
+            "--",
+            "sh",
+            "-c",
+            "sleep 3; exit 4",
+        ])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    drop(emulator);
+
+    // The command still runs to completion, and the readings before the device went away count.
+    let output = run.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("warning: reading the power: "), "{stderr}");
+    assert!(
+        stderr.contains(
+            "
+avg: 42.0 W
+"
+        ),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn record_and_replay() {
+    let emulator = Emulator::start(&["--watts", "42"]);
+    let recording = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(format!("session-{}.ndjson", emulator.port));
+
+    let mut monitor = tapo_power_monitor(PASSWORD)
+        .args(["127.0.0.1", "--port", &emulator.port.to_string()])
+        .args(["monitor", "--output", "ndjson", "--record"])
+        .arg(&recording)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let records = BufReader::new(monitor.stdout.take().unwrap())
+        .lines()
+        .take(3)
+        .count();
+    let _ = monitor.kill();
+    let _ = monitor.wait();
+    assert_eq!(records, 3);
+
+    let recorded = std::fs::read_to_string(&recording).unwrap();
+    let lines: Vec<&str> = recorded.lines().collect();
+    assert!(lines.len() >= 4, "{recorded}");
+    assert!(lines[0].contains(r#""type":"device""#), "{recorded}");
+    assert!(lines[0].contains(r#""model":"P115""#), "{recorded}");
+    assert!(lines[1].contains(r#""watts":42"#), "{recorded}");
+
+    // No device needed to look at it again.
+    let output = tapo_power_monitor(PASSWORD)
+        .arg("replay")
+        .arg(&recording)
+        .args(["--speed", "100x"])
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(&recording);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("current power: 42W"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn analyze_trace() {
+    let trace = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("fridge.ndjson");
+    let readings = ["10", "10", "100", "100", "null", "10"];
+    let mut lines = vec![
+        r#"{"type":"device","name":"fridge","model":"P115","nickname":"Fridge"}"#.to_string(),
+        // Outside of the time window.
+        r#"{"type":"sample","timestamp":"2025-05-31T23:00:00Z","device":"fridge","watts":500}"#
+            .to_string(),
+    ];
+    for (second, watts) in readings.iter().enumerate() {
+        lines.push(format!(
+            r#"{{"type":"sample","timestamp":"2025-06-01T00:00:0{second}Z","device":"fridge","watts":{watts}}}"#
+        ));
+    }
+    std::fs::write(&trace, lines.join("\n")).unwrap();
+
+    let output = tapo_power_monitor(PASSWORD)
+        .arg("analyze")
+        .arg(&trace)
+        .args(["--from", "2025-06-01T00:00:00Z", "--threshold", "50"])
+        .args(["--format", "json"])
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(&trace);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    for expected in [
+        r#""sample_count": 5"#,
+        r#""missing_count": 1"#,
+        r#""max_w": 100"#,
+        r#""p50_w": 10"#,
+        r#""p90_w": 100"#,
+        r#""duty_cycle": 0.5"#,
+        r#""on_cycles": 1"#,
+        r#""time_above_threshold_s": 2.0"#,
+    ] {
+        assert!(stdout.contains(expected), "{expected} in {stdout}");
+    }
+}
+
+#[test]
+#[cfg(feature = "metrics")]
+fn serve_metrics() {
+    let emulator = Emulator::start(&["--watts", "42"]);
+
+    let mut serve = tapo_power_monitor(PASSWORD)
+        .args(["127.0.0.1", "--port", &emulator.port.to_string()])
+        .args(["serve", "--metrics", "--listen", "127.0.0.1:0"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(serve.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    let address = line
+        .trim()
+        .strip_prefix("Serving metrics on http://")
+        .and_then(|url| url.strip_suffix("/metrics"))
+        .unwrap_or_else(|| panic!("unexpected {line:?}"))
+        .to_string();
+
+    let mut metrics = String::new();
+    for _ in 0..50 {
+        metrics = http_get(&address, "/metrics");
+        if metrics.contains("tapo_power_watts{") {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let _ = serve.kill();
+    let _ = serve.wait();
+
+    for expected in [
+        r#"tapo_up{device="127.0.0.1"} 1"#,
+        r#"tapo_power_watts{device="127.0.0.1"} 42"#,
+        r#"tapo_wifi_rssi_dbm{device="127.0.0.1"} -40"#,
+        r#"tapo_scrape_errors_total{device="127.0.0.1"} 0"#,
+        r#"tapo_device_info{device="127.0.0.1",model="P115""#,
+    ] {
+        assert!(metrics.contains(expected), "{expected} in {metrics}");
+    }
+}
+
+#[test]
+#[cfg(feature = "mqtt")]
+fn publish_over_mqtt() {
+    let emulator = Emulator::start(&["--watts", "42"]);
+    let broker = MqttBroker::start();
+
+    let mut serve = tapo_power_monitor(PASSWORD)
+        .args(["127.0.0.1", "--port", &emulator.port.to_string(), "serve"])
+        .args(["--mqtt-url", &format!("mqtt://127.0.0.1:{}", broker.port)])
+        .arg("--home-assistant")
+        .spawn()
+        .unwrap();
+    let mut messages = Vec::new();
+    while let Ok(message) = broker
+        .messages
+        .recv_timeout(std::time::Duration::from_secs(10))
+    {
+        let is_reading = message.topic == "tapo-power-monitor/127.0.0.1";
+        messages.push(message);
+        if is_reading {
+            break;
+        }
+    }
+    let _ = serve.kill();
+    let _ = serve.wait();
+
+    let config = messages
+        .iter()
+        .find(|message| {
+            message.topic == "homeassistant/sensor/tapo_power_monitor_127_0_0_1/power/config"
+        })
+        .unwrap_or_else(|| panic!("no discovery config in {messages:?}"));
+    assert!(config.retain);
+    assert!(
+        config.payload.contains(r#""device_class":"power""#),
+        "{config:?}"
+    );
+    let reading = messages.last().unwrap();
+    assert_eq!(reading.topic, "tapo-power-monitor/127.0.0.1");
+    assert!(reading.payload.contains(r#""watts":42"#), "{reading:?}");
+}
+
+#[test]
+#[cfg(feature = "influx")]
+fn write_to_influxdb() {
+    let emulator = Emulator::start(&["--watts", "42"]);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://{}/api/v2/write?org=home&bucket=power",
+        listener.local_addr().unwrap()
+    );
+
+    let mut serve = tapo_power_monitor(PASSWORD)
+        .args(["127.0.0.1", "--port", &emulator.port.to_string(), "serve"])
+        .args(["--influx-url", &url, "--influx-flush-interval", "1s"])
+        .env("INFLUX_TOKEN", "secret")
+        .spawn()
+        .unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let mut request = BufReader::new(stream);
+    let mut head = Vec::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        request.read_line(&mut line).unwrap();
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse().unwrap();
+        }
+        head.push(line.trim().to_lowercase());
+    }
+    let mut body = vec![0; content_length];
+    request.read_exact(&mut body).unwrap();
+    let _ = request
+        .get_mut()
+        .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n");
+    let _ = serve.kill();
+    let _ = serve.wait();
+
+    assert_eq!(head[0], "post /api/v2/write?org=home&bucket=power http/1.1");
+    assert!(
+        head.contains(&"authorization: token secret".to_string()),
+        "{head:?}"
+    );
+    let body = String::from_utf8(body).unwrap();
+    let line = body.lines().next().unwrap();
+    assert!(
+        line.starts_with("power,device=127.0.0.1,host=") && line.contains(",model=P115 watts=42i,"),
+        "{body}"
+    );
+}
+
+#[test]
+#[cfg(feature = "history")]
+fn log_and_query() {
+    let emulator = Emulator::start(&["--watts", "42"]);
+    let db = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(format!("power-{}.sqlite", emulator.port));
+
+    let mut log = tapo_power_monitor(PASSWORD)
+        .args(["127.0.0.1", "--port", &emulator.port.to_string()])
+        .args(["log", "--db", db.to_str().unwrap()])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(log.stderr.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    assert!(line.starts_with("Logging to"), "{line}");
+    std::thread::sleep(std::time::Duration::from_millis(2500));
+    let _ = log.kill();
+    let _ = log.wait();
+
+    let output = tapo_power_monitor(PASSWORD)
+        .args(["127.0.0.1", "query", "--db", db.to_str().unwrap()])
+        .args(["--resolution", "raw", "--format", "json"])
+        .output()
+        .unwrap();
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", db.display()));
+    }
+    assert!(output.status.success(), "{}", stderr(&output));
+    let rows: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let rows = rows.as_array().unwrap();
+    assert!(rows.len() >= 2, "{rows:?}");
+    for row in rows {
+        assert_eq!(row["device"], "127.0.0.1");
+        assert_eq!(row["mean_w"], 42.0);
+    }
+}
+
+#[test]
+#[cfg(feature = "daemon")]
+fn read_through_daemon() {
+    let emulator = Emulator::start(&["--watts", "42"]);
+    let config = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(format!("daemon-{}.toml", emulator.port));
+    std::fs::write(
+        &config,
+        format!("[devices]\nlab-bench = \"127.0.0.1:{}\"\n", emulator.port),
+    )
+    .unwrap();
+
+    let mut daemon = tapo_power_monitor(PASSWORD)
+        .args(["--config", config.to_str().unwrap()])
+        .args(["daemon", "--listen", "127.0.0.1:0"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(daemon.stderr.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let address = line
+        .trim()
+        .strip_prefix("Serving lab-bench on http://")
+        .unwrap_or_else(|| panic!("unexpected {line:?}"))
+        .to_string();
+
+    let mut devices = String::new();
+    for _ in 0..50 {
+        devices = http_get(&address, "/devices");
+        if devices.contains(r#""watts":42"#) {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let history = http_get(&address, "/devices/lab-bench/history");
+    let output = tapo_power_monitor(PASSWORD)
+        .args(["--daemon", "--daemon-url", &format!("http://{address}")])
+        .args(["measure", "--samples", "2"])
+        .output()
+        .unwrap();
+    let _ = daemon.kill();
+    let _ = daemon.wait();
+    let _ = std::fs::remove_file(&config);
+
+    assert!(
+        devices.contains(r#""id":"lab-bench","model":"P115""#),
+        "{devices}"
+    );
+    assert!(history.contains(r#""watts":42"#), "{history}");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("avg: 42.0 W"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn model_is_detected() {
+    let plug = Emulator::start(&["--model", "P110M"]);
+    let output = plug.run(PASSWORD, &["measure", "--samples", "1"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("avg: 42.0 W"),
+        "{}",
+        stdout(&output)
+    );
+
+    let strip = Emulator::start(&["--model", "P304M"]);
+    let output = strip.run(PASSWORD, &["measure", "--samples", "1"]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("P304M power strip"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn device_from_config_file() {
+    let emulator = Emulator::start(&["--watts", "55"]);
+    let config = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(format!("config-{}.toml", emulator.port));
+    std::fs::write(
+        &config,
+        format!(
+            "format = \"json\"\n[devices]\nlab-bench = \"127.0.0.1:{}\"\n",
+            emulator.port
+        ),
+    )
+    .unwrap();
+
+    let output = tapo_power_monitor(PASSWORD)
+        .args(["--config", config.to_str().unwrap(), "lab-bench", "measure"])
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(&config);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains(r#""device": "lab-bench""#),
+        "{}",
+        stdout(&output)
+    );
+    assert!(
+        stdout(&output).contains(r#""mean_w": 55.0"#),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn password_sources() {
+    let emulator = Emulator::start(&[]);
+    let port = emulator.port.to_string();
+    let password_file =
+        std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("password-{port}"));
+    std::fs::write(&password_file, format!("{PASSWORD}\n")).unwrap();
+    let measure = |source: &[&str]| {
+        tapo_power_monitor(PASSWORD)
+            .env_remove("TAPO_PASSWORD")
+            .args(source)
+            .args(["127.0.0.1", "--port", &port, "measure", "--samples", "1"])
+            .output()
+            .unwrap()
+    };
+
+    let output = measure(&["--password-command", &format!("echo '{PASSWORD}'")]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let set_mode = |mode| {
+            std::fs::set_permissions(&password_file, std::fs::Permissions::from_mode(mode)).unwrap()
+        };
+
+        set_mode(0o600);
+        let output = measure(&["--password-file", password_file.to_str().unwrap()]);
+        assert!(output.status.success(), "{}", stderr(&output));
+
+        set_mode(0o644);
+        let output = measure(&["--password-file", password_file.to_str().unwrap()]);
+        assert!(!output.status.success());
+        assert!(
+            stderr(&output).contains("readable by all users"),
+            "{}",
+            stderr(&output)
+        );
+    }
+    let _ = std::fs::remove_file(&password_file);
+}
+
+#[test]
+fn discover() {
+    let emulator = Emulator::start(&["--discovery", "127.0.0.1:0"]);
+    let target = format!("127.0.0.1:{}", emulator.discovery_port.unwrap());
+
+    let output = tapo_power_monitor(PASSWORD)
+        .args(["--discovery-target", &target, "discover", "--wait", "1s"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    let row = stdout
+        .lines()
+        .find(|line| line.starts_with(&format!("127.0.0.1:{} ", emulator.port)))
+        .unwrap_or_else(|| panic!("emulator is listed:\n{stdout}"));
+    assert!(row.contains("P115(EU)"), "{row}");
+    assert!(row.contains("yes"), "{row}");
+    assert!(row.ends_with("Emulated plug"), "{row}");
+}
+
+#[test]
+fn device_by_nickname_or_mac() {
+    let emulator = Emulator::start(&["--discovery", "127.0.0.1:0", "--watts", "66"]);
+    let target = format!("127.0.0.1:{}", emulator.discovery_port.unwrap());
+    let cache =
+        std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("cache-{}", emulator.port));
+    let measure = |lookup: &[&str], target: &str| {
+        let output = tapo_power_monitor(PASSWORD)
+            .env("XDG_CACHE_HOME", &cache)
+            .args(["--discovery-target", target])
+            .args(lookup)
+            .args(["measure", "--samples", "1"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert!(
+            stdout(&output).contains("avg: 66.0 W"),
+            "{}",
+            stdout(&output)
+        );
+    };
+
+    measure(&["--device-name", "emulated plug"], &target);
+    // Found in the cache, nothing answers discovery there.
+    measure(&["--mac", "00:00:5e:00:53:01"], "127.0.0.1:9");
+    let _ = std::fs::remove_dir_all(&cache);
+}
+
+#[test]
+fn wrong_password_is_rejected() {
+    for protocol in ["klap", "passthrough"] {
+        let emulator = Emulator::start(&["--protocol", protocol]);
+
+        let output = emulator.run("hunter2", &["measure"]);
+        assert!(!output.status.success());
+        assert!(
+            stderr(&output).contains("InvalidCredentials"),
+            "{protocol}: {}",
+            stderr(&output)
+        );
+        assert!(
+            stderr(&output).contains("rejected the credentials"),
+            "{protocol}: {}",
+            stderr(&output)
+        );
+    }
+}
+
+#[test]
+fn nothing_listening() {
+    // Grab a free port and release it again so that nobody is listening on it.
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let output = run(port, PASSWORD, &["measure"]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Connecting to the device"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(
+        stderr(&output).contains("refused the connection"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn slow_device_times_out() {
+    let emulator = Emulator::start(&["--response-delay", "5s"]);
+
+    let started = std::time::Instant::now();
+    let output = emulator.run(PASSWORD, &["--timeout", "1s", "measure"]);
+    assert!(!output.status.success());
+    assert!(started.elapsed().as_secs() < 5, "{:?}", started.elapsed());
+    assert!(
+        stderr(&output).contains("didn't answer in time"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn doctor() {
+    let emulator = Emulator::start(&[]);
+
+    let output = emulator.run(PASSWORD, &["doctor"]);
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert!(
+        stdout(&output).contains(r#"model: P115 "Emulated plug""#),
+        "{}",
+        stdout(&output)
+    );
+    assert!(
+        stdout(&output).contains("reading: 42 W"),
+        "{}",
+        stdout(&output)
+    );
+
+    let output = emulator.run("hunter2", &["doctor"]);
+    assert!(!output.status.success());
+    assert!(
+        stdout(&output).contains("✗ login: Tapo: InvalidCredentials"),
+        "{}",
+        stdout(&output)
+    );
+}
@@ -0,0 +1,297 @@
+//! End to end tests running `tapo-power-monitor` against its simulated plug.
+
+use std::process::{Command, Output};
+#[cfg(feature = "daemon")]
+use std::{
+    io::{BufRead, BufReader},
+    process::Stdio,
+};
+
+/// The tool without credentials, and without picking up the config of whoever runs the tests.
+fn tapo_power_monitor(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_tapo-power-monitor"))
+        .env("XDG_CONFIG_HOME", env!("CARGO_TARGET_TMPDIR"))
+        .env_remove("TAPO_USERNAME")
+        .env_remove("TAPO_PASSWORD")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// The tool reading from a simulated plug.
+fn simulate(profile: &str, args: &[&str]) -> Output {
+    tapo_power_monitor(&[&["--simulate", profile], args].concat())
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn measure_without_credentials() {
+    let output = simulate("constant:50", &["measure", "--samples", "2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("avg: 50.0 W"),
+        "{}",
+        stdout(&output)
+    );
+    assert!(
+        stdout(&output).contains("samples: [50, 50]"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn replay_a_trace() {
+    let trace = format!("{}/simulated-trace.csv", env!("CARGO_TARGET_TMPDIR"));
+    std::fs::write(&trace, "timestamp,watts\n0,7\n1,9\n").unwrap();
+
+    let output = simulate(
+        &format!("replay:{trace}"),
+        &["measure", "--samples", "2", "--format", "json"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    for field in [r#""min_w": 7"#, r#""max_w": 9"#] {
+        assert!(stdout(&output).contains(field), "{}", stdout(&output));
+    }
+}
+
+#[test]
+fn invalid_profile_is_rejected() {
+    let output = simulate("sine:50", &["measure"]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Unknown profile \"sine\""),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn measure_for_a_duration() {
+    let output = simulate(
+        "constant:50",
+        &[
+            "measure",
+            "--duration",
+            "2s",
+            "--interval",
+            "100ms",
+            "--format",
+            "json",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    // The device can't be sampled that often.
+    assert!(
+        stderr(&output).contains("sampling at that rate instead"),
+        "{}",
+        stderr(&output)
+    );
+    for field in [r#""sample_interval_s": 1.0"#, r#""sample_count": 2"#] {
+        assert!(stdout(&output).contains(field), "{}", stdout(&output));
+    }
+}
+
+#[test]
+fn measure_until_stable() {
+    let output = simulate("constant:50", &["measure", "--until-stable", "1%"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("stable after 5 samples"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(
+        stdout(&output).contains("avg: 50.0 W"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn measure_gives_up_when_not_stable() {
+    let output = simulate(
+        "square:0:100:2s",
+        &["measure", "--until-stable", "0.1W", "--max-duration", "2s"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("warning: not stable within ±0.1 W"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+/// Measures two samples of 50 W in the given format.
+fn measure_as(format: &str) -> String {
+    let output = simulate(
+        "constant:50",
+        &["measure", "--samples", "2", "--format", format],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    stdout(&output)
+}
+
+#[test]
+fn measure_as_text() {
+    let output = measure_as("human");
+    for line in [
+        "avg: 50.0 W +-0.0 W",
+        "min: 50 W",
+        "max: 50 W",
+        "samples: [50, 50]",
+    ] {
+        assert!(output.lines().any(|l| l == line), "{output}");
+    }
+}
+
+#[test]
+fn measure_as_json() {
+    let measurement: serde_json::Value = serde_json::from_str(&measure_as("json")).unwrap();
+    assert_eq!(measurement["device"], "simulated");
+    assert_eq!(measurement["sample_count"], 2);
+    assert_eq!(measurement["mean_w"], 50.0);
+    assert_eq!(measurement["samples"][1]["watts"], 50);
+}
+
+#[test]
+fn measure_as_csv() {
+    let output = measure_as("csv");
+    let mut reader = csv::Reader::from_reader(output.as_bytes());
+    let header = reader.headers().unwrap().clone();
+    let column = |name: &str| header.iter().position(|column| column == name).unwrap();
+    let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+
+    // One row per sample, repeating the summary.
+    assert_eq!(rows.len(), 2, "{output}");
+    for row in &rows {
+        assert_eq!(&row[column("sample_count")], "2");
+        assert_eq!(&row[column("mean_w")], "50.0");
+        assert_eq!(&row[column("watts")], "50");
+    }
+    assert!(rows[0][column("timestamp")] < rows[1][column("timestamp")]);
+}
+
+#[test]
+fn measure_as_toml() {
+    let measurement: toml::Table = measure_as("toml").parse().unwrap();
+    assert_eq!(measurement["device"].as_str(), Some("simulated"));
+    assert_eq!(measurement["sample_count"].as_integer(), Some(2));
+    assert_eq!(measurement["mean_w"].as_float(), Some(50.0));
+    assert_eq!(measurement["samples"].as_array().map(Vec::len), Some(2));
+}
+
+#[test]
+fn run_passes_on_the_exit_code() {
+    let output = simulate(
+        "constant:50",
+        &[
+            "run",
+            "--baseline-samples",
+            "0",
+            "--",
+            "sh",
+            "-c",
+            "sleep 1.5; echo done; exit 3",
+        ],
+    );
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    // The command's own output is left alone.
+    assert_eq!(stdout(&output), "done\n");
+    assert!(
+        stderr(&output).contains("\navg: 50.0 W\n"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(
+        stderr(&output).contains("\npeak: 50 W\n"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn run_reports_on_short_commands() {
+    let output = simulate("constant:50", &["run", "--baseline-samples", "0", "true"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("\navg: 50.0 W\n"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+#[cfg(feature = "history")]
+fn query_needs_an_existing_database() {
+    let db = format!("{}/never-logged.sqlite", env!("CARGO_TARGET_TMPDIR"));
+    let _ = std::fs::remove_file(&db);
+
+    let output = tapo_power_monitor(&["query", "--db", &db]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("doesn't exist"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!std::path::Path::new(&db).exists());
+}
+
+#[test]
+#[cfg(feature = "daemon")]
+fn measure_through_a_slower_daemon() {
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_tapo-power-monitor"))
+        .env("XDG_CONFIG_HOME", env!("CARGO_TARGET_TMPDIR"))
+        .args([
+            "--simulate",
+            "constant:50",
+            "daemon",
+            "--listen",
+            "127.0.0.1:0",
+        ])
+        .args(["--interval", "2s"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(daemon.stderr.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let address = line
+        .trim()
+        .strip_prefix("Serving simulated on http://")
+        .unwrap_or_else(|| panic!("unexpected {line:?}"))
+        .to_string();
+
+    let output = tapo_power_monitor(&[
+        "--daemon",
+        "--daemon-url",
+        &format!("http://{address}"),
+        "measure",
+        "--samples",
+        "2",
+        "--format",
+        "json",
+    ]);
+    let _ = daemon.kill();
+    let _ = daemon.wait();
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let measurement: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let timestamp = |index: usize| {
+        let timestamp = measurement["samples"][index]["timestamp"].as_str().unwrap();
+        chrono::DateTime::parse_from_rfc3339(timestamp).unwrap()
+    };
+    // The daemon's readings are only taken every 2 seconds, each counts once.
+    assert!(
+        timestamp(1) - timestamp(0) >= chrono::TimeDelta::milliseconds(1500),
+        "{measurement}"
+    );
+}